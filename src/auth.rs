@@ -1,28 +1,54 @@
 use std::{
     collections::{HashMap, HashSet},
+    net::IpAddr,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, ensure};
+use ipnetwork::IpNetwork;
 use ordered_float::NotNan;
 use serde::Deserialize;
-use serde_with::serde_as;
-use thegraph_core::SubgraphId;
+use serde_with::{serde_as, DisplayFromStr};
+use thegraph_core::{DeploymentId, SubgraphId};
 use tokio::sync::watch;
 
+pub use self::{
+    jwt::JwtIssuers,
+    rate_limit::{RateLimit, RateLimiter},
+    spend::{SpendTracker, BUCKET_SECS as SPEND_BUCKET_SECS},
+};
+use crate::{query_policy::QueryPolicy, time::unix_timestamp};
+
+pub mod admin;
+mod jwt;
+pub mod key_file;
+mod rate_limit;
+mod spend;
+
 #[derive(Clone, Debug, Default)]
 pub struct AuthSettings {
     pub key: String,
     pub user: String,
     pub authorized_subgraphs: Vec<SubgraphId>,
+    pub authorized_deployments: Vec<DeploymentId>,
+    pub authorized_chains: Vec<String>,
+    pub denied_subgraphs: Vec<SubgraphId>,
+    pub denied_deployments: Vec<DeploymentId>,
     pub budget_usd: Option<NotNan<f64>>,
+    pub rate_limit: Option<RateLimit>,
+    pub query_policy: QueryPolicy,
 }
 
 impl AuthSettings {
-    /// Check if the given subgraph is authorized. If the set of authorized subgraphs is empty, then
-    /// any subgraph is authorized.
+    /// Check if the given subgraph is authorized. If the sets of authorized subgraphs and
+    /// deployments are both empty, then any subgraph that is not denied is authorized.
     pub fn is_subgraph_authorized(&self, subgraph: &SubgraphId) -> bool {
-        self.authorized_subgraphs.is_empty() || self.authorized_subgraphs.contains(subgraph)
+        if self.is_subgraph_denied(subgraph) {
+            return false;
+        }
+        (self.authorized_subgraphs.is_empty() && self.authorized_deployments.is_empty())
+            || self.authorized_subgraphs.contains(subgraph)
     }
 
     pub fn is_any_deployment_subgraph_authorized(&self, subgraphs: &[SubgraphId]) -> bool {
@@ -30,6 +56,34 @@ impl AuthSettings {
             .iter()
             .any(|subgraph| self.is_subgraph_authorized(subgraph))
     }
+
+    /// Check if the given deployment is authorized, either explicitly or by any of its subgraphs.
+    /// A denied deployment is never authorized.
+    pub fn is_deployment_authorized(
+        &self,
+        deployment: &DeploymentId,
+        subgraphs: &[SubgraphId],
+    ) -> bool {
+        if self.is_deployment_denied(deployment) {
+            return false;
+        }
+        self.authorized_deployments.contains(deployment)
+            || self.is_any_deployment_subgraph_authorized(subgraphs)
+    }
+
+    /// Check if the given chain is authorized. If the set of authorized chains is empty, then any
+    /// chain is authorized.
+    pub fn is_chain_authorized(&self, chain: &str) -> bool {
+        self.authorized_chains.is_empty() || self.authorized_chains.iter().any(|c| c == chain)
+    }
+
+    pub fn is_subgraph_denied(&self, subgraph: &SubgraphId) -> bool {
+        self.denied_subgraphs.contains(subgraph)
+    }
+
+    pub fn is_deployment_denied(&self, deployment: &DeploymentId) -> bool {
+        self.denied_deployments.contains(deployment)
+    }
 }
 
 #[serde_as]
@@ -43,8 +97,36 @@ pub struct APIKey {
     pub max_budget_usd: Option<NotNan<f64>>,
     #[serde(default)]
     pub subgraphs: Vec<SubgraphId>,
+    /// Authorized deployments, in addition to the deployments of the authorized subgraphs
+    #[serde(default)]
+    pub deployments: Vec<DeploymentId>,
+    /// Authorized chains. Any chain is authorized if empty.
+    #[serde(default)]
+    pub chains: Vec<String>,
+    /// Subgraphs that can't be queried, even if otherwise authorized
+    #[serde(default)]
+    pub denied_subgraphs: Vec<SubgraphId>,
+    /// Deployments that can't be queried, even if otherwise authorized
+    #[serde(default)]
+    pub denied_deployments: Vec<DeploymentId>,
     #[serde(default)]
     pub domains: Vec<String>,
+    /// Authorized client IP networks. Any client IP is authorized if empty.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub allowed_ips: Vec<IpNetwork>,
+    /// Overrides the default API key rate limit
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Restrictions of the queries made with the API key
+    #[serde(default)]
+    pub query_policy: QueryPolicy,
+    /// Maximum query fees, in USD, spent by the API key over the spend tracking period. Enforced
+    /// using the locally tracked spend, in addition to the `MONTHLY_CAP_REACHED` query status, even
+    /// if payment isn't required.
+    #[serde_as(as = "Option<serde_with::TryFromInto<f64>>")]
+    #[serde(default)]
+    pub monthly_cap_usd: Option<NotNan<f64>>,
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize)]
@@ -63,21 +145,37 @@ pub struct AuthContext {
     pub payment_required: bool,
     pub api_keys: watch::Receiver<HashMap<String, APIKey>>,
     pub special_api_keys: Arc<HashSet<String>>,
+    pub rate_limiter: RateLimiter,
+    pub spend_tracker: SpendTracker,
+    /// Issuers of the JWTs accepted in place of API keys. JWT authentication is disabled if empty.
+    pub jwt_issuers: Arc<JwtIssuers>,
+    /// Maximum lifetime of the accepted JWTs
+    pub jwt_max_lifetime: Duration,
+    /// Number of trusted proxies in front of the gateway. If not zero, the client IP is taken from
+    /// the `X-Forwarded-For` header entry appended by the outermost trusted proxy, instead of the
+    /// connecting address.
+    pub trusted_proxy_hops: usize,
 }
 
 impl AuthContext {
     /// Parse an authorization token into its corresponding settings, and check that the query
     /// should be handled.
-    pub fn check(&self, token: &str, domain: &str) -> anyhow::Result<AuthSettings> {
+    pub fn check(
+        &self,
+        token: &str,
+        domain: &str,
+        client_ip: Option<IpAddr>,
+    ) -> anyhow::Result<AuthSettings> {
         ensure!(!token.is_empty(), "missing API key");
+        if !self.jwt_issuers.is_empty() && jwt::is_jwt(token) {
+            return self.check_jwt(token, domain, client_ip);
+        }
         parse_api_key(token).ok_or_else(|| anyhow!("malformed API key"))?;
 
         if self.special_api_keys.contains(token) {
             return Ok(AuthSettings {
                 key: token.to_string(),
-                user: String::new(),
-                authorized_subgraphs: vec![],
-                budget_usd: None,
+                ..Default::default()
             });
         }
 
@@ -99,19 +197,94 @@ impl AuthContext {
                 }
             }
         }
+        if let Some(monthly_cap_usd) = api_key.monthly_cap_usd {
+            ensure!(
+                self.spend_tracker.spend(&api_key.key) < *monthly_cap_usd,
+                "spend limit exceeded for this API key"
+            );
+        }
 
         ensure!(
             is_domain_authorized(api_key.domains.as_slice(), domain),
             "domain not authorized by user"
         );
+        ensure!(
+            is_ip_authorized(api_key.allowed_ips.as_slice(), client_ip),
+            "client IP not authorized by user"
+        );
 
         Ok(AuthSettings {
             key: api_key.key.clone(),
             user: api_key.user_address.clone(),
             authorized_subgraphs: api_key.subgraphs.clone(),
+            authorized_deployments: api_key.deployments.clone(),
+            authorized_chains: api_key.chains.clone(),
+            denied_subgraphs: api_key.denied_subgraphs.clone(),
+            denied_deployments: api_key.denied_deployments.clone(),
             budget_usd: api_key.max_budget_usd,
+            rate_limit: api_key.rate_limit,
+            query_policy: api_key.query_policy.clone(),
         })
     }
+
+    /// Verify a signed JWT, and check that the query should be handled.
+    ///
+    /// The JWT claims take the place of the API key settings. The settings key is derived from
+    /// the issuer and subject of the token, so the subject is rate limited with the default API
+    /// key rate limit, unless overridden by the claims, and its spend is tracked like an API key's.
+    fn check_jwt(
+        &self,
+        token: &str,
+        domain: &str,
+        client_ip: Option<IpAddr>,
+    ) -> anyhow::Result<AuthSettings> {
+        let claims = jwt::verify(
+            token,
+            &self.jwt_issuers,
+            self.jwt_max_lifetime,
+            unix_timestamp() / 1_000,
+        )?;
+        let key = format!("{}:{}", claims.iss, claims.sub);
+
+        ensure!(
+            is_domain_authorized(claims.domains.as_slice(), domain),
+            "domain not authorized by user"
+        );
+        ensure!(
+            is_ip_authorized(claims.allowed_ips.as_slice(), client_ip),
+            "client IP not authorized by user"
+        );
+        if let Some(monthly_cap_usd) = claims.monthly_cap_usd {
+            ensure!(
+                self.spend_tracker.spend(&key) < monthly_cap_usd,
+                "spend limit exceeded for this API key"
+            );
+        }
+
+        Ok(AuthSettings {
+            key,
+            user: claims.user,
+            authorized_subgraphs: claims.subgraphs,
+            authorized_deployments: claims.deployments,
+            authorized_chains: claims.chains,
+            budget_usd: claims.max_budget.and_then(|b| NotNan::new(b).ok()),
+            rate_limit: claims.rate_limit,
+            query_policy: claims.query_policy,
+            ..Default::default()
+        })
+    }
+
+    /// Check that the query is within the rate limits of its API key and user. Special API keys
+    /// are not rate limited.
+    ///
+    /// Returns the time to wait before retrying if a rate limit is exceeded.
+    pub fn check_rate_limit(&self, auth: &AuthSettings) -> Result<(), Duration> {
+        if self.special_api_keys.contains(&auth.key) {
+            return Ok(());
+        }
+        self.rate_limiter
+            .check(&auth.key, auth.rate_limit, &auth.user)
+    }
 }
 
 fn parse_api_key(token: &str) -> Option<[u8; 16]> {
@@ -144,11 +317,31 @@ pub fn is_domain_authorized<S: AsRef<str>>(authorized: &[S], origin: &str) -> bo
             .any(|pattern| match_domain(pattern.as_ref(), origin))
 }
 
+/// Check if the client IP is authorized.
+///
+/// If the authorized networks set is empty, all clients are considered authorized. Otherwise, the
+/// client IP must be known and within one of the authorized networks.
+pub fn is_ip_authorized(authorized: &[IpNetwork], client_ip: Option<IpAddr>) -> bool {
+    if authorized.is_empty() {
+        return true;
+    }
+    match client_ip {
+        Some(ip) => authorized.iter().any(|network| network.contains(ip)),
+        None => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use thegraph_core::alloy::primitives::hex;
+    use std::collections::HashMap;
+
+    use ordered_float::NotNan;
+    use thegraph_core::{alloy::primitives::hex, DeploymentId, SubgraphId};
+    use tokio::sync::watch;
 
-    use super::{is_domain_authorized, parse_api_key};
+    use super::{
+        is_domain_authorized, is_ip_authorized, parse_api_key, APIKey, AuthContext, AuthSettings,
+    };
 
     #[test]
     fn parse_invalid_length_api_key() {
@@ -209,4 +402,99 @@ mod tests {
             assert!(is_domain_authorized(&[] as &[&str], input));
         }
     }
+
+    #[test]
+    fn authorized_deployments_and_chains() {
+        let subgraph = |n: u8| SubgraphId::new([n; 32].into());
+        let deployment = |n: u8| DeploymentId::new([n; 32].into());
+
+        let unrestricted = AuthSettings::default();
+        assert!(unrestricted.is_subgraph_authorized(&subgraph(1)));
+        assert!(unrestricted.is_deployment_authorized(&deployment(1), &[subgraph(1)]));
+        assert!(unrestricted.is_chain_authorized("mainnet"));
+
+        let settings = AuthSettings {
+            authorized_subgraphs: vec![subgraph(1)],
+            authorized_deployments: vec![deployment(2), deployment(3)],
+            authorized_chains: vec!["mainnet".to_string()],
+            denied_deployments: vec![deployment(3)],
+            ..Default::default()
+        };
+        assert!(settings.is_subgraph_authorized(&subgraph(1)));
+        assert!(!settings.is_subgraph_authorized(&subgraph(2)));
+        assert!(settings.is_deployment_authorized(&deployment(1), &[subgraph(1)]));
+        assert!(settings.is_deployment_authorized(&deployment(2), &[subgraph(2)]));
+        assert!(!settings.is_deployment_authorized(&deployment(3), &[subgraph(1)]));
+        assert!(!settings.is_deployment_authorized(&deployment(4), &[subgraph(2)]));
+        assert!(settings.is_chain_authorized("mainnet"));
+        assert!(!settings.is_chain_authorized("gnosis"));
+
+        let denied = AuthSettings {
+            denied_subgraphs: vec![subgraph(1)],
+            ..Default::default()
+        };
+        assert!(!denied.is_subgraph_authorized(&subgraph(1)));
+        assert!(denied.is_subgraph_authorized(&subgraph(2)));
+        assert!(!denied.is_deployment_authorized(&deployment(1), &[subgraph(1)]));
+    }
+
+    #[test]
+    fn authorized_ips() {
+        let authorized = [
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ];
+
+        assert!(is_ip_authorized(
+            &authorized,
+            Some("10.1.2.3".parse().unwrap())
+        ));
+        assert!(is_ip_authorized(
+            &authorized,
+            Some("2001:db8::1".parse().unwrap())
+        ));
+        assert!(!is_ip_authorized(
+            &authorized,
+            Some("192.168.0.1".parse().unwrap())
+        ));
+        assert!(!is_ip_authorized(&authorized, None));
+        // check all authorized when authorized set is empty
+        assert!(is_ip_authorized(&[], None));
+    }
+
+    #[test]
+    fn enforce_monthly_cap_without_payment() {
+        //* Given
+        let key = "0123456789abcdef0123456789abcdef";
+        let ctx = AuthContext {
+            payment_required: false,
+            api_keys: watch::channel(HashMap::from([(
+                key.to_string(),
+                APIKey {
+                    key: key.to_string(),
+                    monthly_cap_usd: Some(NotNan::new(1.0).unwrap()),
+                    ..Default::default()
+                },
+            )]))
+            .1,
+            special_api_keys: Default::default(),
+            rate_limiter: Default::default(),
+            spend_tracker: Default::default(),
+            jwt_issuers: Default::default(),
+            jwt_max_lifetime: Default::default(),
+            trusted_proxy_hops: 0,
+        };
+        let under_cap = ctx.check(key, "", None).map(|_| ());
+
+        //* When
+        ctx.spend_tracker.add(key, 1.0);
+        let over_cap = ctx.check(key, "", None).map(|_| ());
+
+        //* Then
+        assert!(under_cap.is_ok());
+        assert_eq!(
+            over_cap.unwrap_err().to_string(),
+            "spend limit exceeded for this API key"
+        );
+    }
 }
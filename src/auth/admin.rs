@@ -0,0 +1,141 @@
+//! Admin webhook applying API key updates and revocations immediately.
+//!
+//! The API keys are otherwise only refreshed by their source (e.g. every 30 seconds for the Studio
+//! endpoint). Updates pushed to the webhook are applied to the API keys map right away, and the
+//! next full refresh from the source reconciles the map afterwards.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use headers::{authorization::Bearer, Authorization, HeaderMapExt as _};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+
+use super::APIKey;
+use crate::json::{json_response, JsonResponse};
+
+/// State of the API key admin webhook.
+#[derive(Clone)]
+pub struct ApiKeyAdmin {
+    /// Bearer token required to call the webhook
+    pub auth_token: String,
+    pub api_keys: Arc<watch::Sender<HashMap<String, APIKey>>>,
+}
+
+/// API key updates pushed to the admin webhook.
+#[derive(Debug, Default, Deserialize)]
+pub struct ApiKeyUpdates {
+    /// API keys to insert, or replace
+    #[serde(default)]
+    pub updates: Vec<APIKey>,
+    /// API keys to remove
+    #[serde(default)]
+    pub revocations: Vec<String>,
+}
+
+pub async fn handle_api_key_updates(
+    State(admin): State<ApiKeyAdmin>,
+    headers: HeaderMap,
+    Json(payload): Json<ApiKeyUpdates>,
+) -> Result<JsonResponse, (StatusCode, String)> {
+    let authorized = match headers.typed_get::<Authorization<Bearer>>() {
+        Some(Authorization(bearer)) => constant_time_eq(bearer.token(), &admin.auth_token),
+        None => false,
+    };
+    if !authorized {
+        return Err((StatusCode::UNAUTHORIZED, "unauthorized".to_string()));
+    }
+
+    let (updated, revoked) = apply(&admin.api_keys, payload);
+    tracing::info!(updated, revoked, "applied API key updates");
+    Ok(json_response(
+        [],
+        json!({ "updated": updated, "revoked": revoked }),
+    ))
+}
+
+/// Apply the updates to the API keys map. Returns the number of updated and revoked API keys.
+fn apply(
+    api_keys: &watch::Sender<HashMap<String, APIKey>>,
+    payload: ApiKeyUpdates,
+) -> (usize, usize) {
+    let updated = payload.updates.len();
+    let mut revoked = 0;
+    api_keys.send_modify(|api_keys| {
+        for api_key in payload.updates {
+            api_keys.insert(api_key.key.clone(), api_key);
+        }
+        for key in &payload.revocations {
+            if api_keys.remove(key).is_some() {
+                revoked += 1;
+            }
+        }
+    });
+    (updated, revoked)
+}
+
+/// Compare the strings in constant time, for their length, to avoid leaking the auth token.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0_u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::watch;
+
+    use super::{apply, constant_time_eq, ApiKeyUpdates};
+    use crate::auth::APIKey;
+
+    fn api_key(key: &str, user_address: &str) -> APIKey {
+        APIKey {
+            key: key.to_string(),
+            user_address: user_address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn apply_api_key_updates() {
+        //* Given
+        let (tx, rx) = watch::channel(HashMap::from([
+            ("a".to_string(), api_key("a", "0x01")),
+            ("b".to_string(), api_key("b", "0x01")),
+        ]));
+
+        //* When
+        let result = apply(
+            &tx,
+            ApiKeyUpdates {
+                updates: vec![api_key("a", "0x02"), api_key("c", "0x03")],
+                revocations: vec!["b".to_string(), "unknown".to_string()],
+            },
+        );
+
+        //* Then
+        assert_eq!(result, (2, 1));
+        let api_keys = rx.borrow();
+        assert_eq!(api_keys.len(), 2);
+        assert_eq!(api_keys["a"].user_address, "0x02");
+        assert_eq!(api_keys["c"].user_address, "0x03");
+        assert!(!api_keys.contains_key("b"));
+    }
+
+    #[test]
+    fn compare_auth_tokens() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secrets"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+}
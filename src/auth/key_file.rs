@@ -0,0 +1,193 @@
+//! API keys loaded from a local file.
+//!
+//! The file is either a JSON array of API keys, or a CSV file (with a `.csv` extension) of rows of
+//! `key,user_address[,query_status[,max_budget[,subgraphs[,domains]]]]`, where the subgraphs and
+//! domains are separated by spaces. Rows with more fields are rejected. The other API key settings
+//! (`deployments`, `chains`, `denied_subgraphs`, `denied_deployments`, `allowed_ips`,
+//! `rate_limit`, `query_policy` and `monthly_cap_usd`) can't be expressed in CSV, and require the
+//! JSON format.
+//!
+//! The file is polled for modifications, and the API keys are reloaded when it changes. If the
+//! modified file is invalid, the previous API keys are kept.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context as _};
+use ordered_float::NotNan;
+use tokio::{
+    sync::watch,
+    time::{interval, MissedTickBehavior},
+};
+
+use super::{APIKey, QueryStatus};
+
+/// Interval between checks of the file modification time.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Load the API keys from the file into the API keys map, and spawn a task reloading them when the
+/// file is modified.
+pub fn api_keys(
+    path: PathBuf,
+    tx: Arc<watch::Sender<HashMap<String, APIKey>>>,
+) -> anyhow::Result<()> {
+    let mut modified = modified_time(&path)?;
+    let api_keys = load(&path)?;
+    tracing::info!(api_keys = api_keys.len(), path = %path.display());
+    tx.send_replace(api_keys);
+
+    tokio::spawn(async move {
+        let mut interval = interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+
+            match modified_time(&path) {
+                Ok(t) if t != modified => modified = t,
+                Ok(_) => continue,
+                Err(api_keys_file_err) => {
+                    tracing::error!(%api_keys_file_err);
+                    continue;
+                }
+            };
+            match load(&path) {
+                Ok(api_keys) => {
+                    tracing::info!(api_keys = api_keys.len(), "reloaded API keys file");
+                    if let Err(api_keys_send_err) = tx.send(api_keys) {
+                        tracing::error!(%api_keys_send_err);
+                    }
+                }
+                Err(api_keys_file_err) => tracing::error!(%api_keys_file_err),
+            };
+        }
+    });
+
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> anyhow::Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn load(path: &Path) -> anyhow::Result<HashMap<String, APIKey>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let api_keys: Vec<APIKey> = if path.extension().is_some_and(|ext| ext == "csv") {
+        parse_csv(&content)?
+    } else {
+        serde_json::from_str(&content).context("invalid API keys file")?
+    };
+    Ok(api_keys.into_iter().map(|k| (k.key.clone(), k)).collect())
+}
+
+/// Parse the API keys from CSV rows. Empty lines, comments (starting with `#`), and a `key,...`
+/// header row are skipped.
+fn parse_csv(content: &str) -> anyhow::Result<Vec<APIKey>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#') && !line.starts_with("key,")
+        })
+        .map(|(n, line)| {
+            parse_csv_row(line).with_context(|| format!("invalid API keys file row {}", n + 1))
+        })
+        .collect()
+}
+
+fn parse_csv_row(line: &str) -> anyhow::Result<APIKey> {
+    let mut fields = line.split(',').map(str::trim);
+    let key = fields
+        .next()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| anyhow!("missing key"))?;
+    let user_address = fields
+        .next()
+        .ok_or_else(|| anyhow!("missing user address"))?;
+    let query_status = match fields.next().unwrap_or("") {
+        "" | "ACTIVE" => QueryStatus::Active,
+        "SERVICE_SHUTOFF" => QueryStatus::ServiceShutoff,
+        "MONTHLY_CAP_REACHED" => QueryStatus::MonthlyCapReached,
+        status => return Err(anyhow!("invalid query status: {status}")),
+    };
+    let max_budget_usd: Option<NotNan<f64>> = match fields.next().unwrap_or("") {
+        "" => None,
+        budget => Some(
+            budget
+                .parse::<f64>()
+                .ok()
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(|| anyhow!("invalid max budget: {budget}"))?,
+        ),
+    };
+    let subgraphs = fields
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .map(|s| s.parse().map_err(|_| anyhow!("invalid subgraph: {s}")))
+        .collect::<anyhow::Result<_>>()?;
+    let domains = fields
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    if fields.next().is_some() {
+        return Err(anyhow!("unexpected fields after domains"));
+    }
+    Ok(APIKey {
+        key: key.to_string(),
+        user_address: user_address.to_string(),
+        query_status,
+        max_budget_usd,
+        subgraphs,
+        domains,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::parse_csv;
+    use crate::auth::QueryStatus;
+
+    #[test]
+    fn parse_csv_api_keys() {
+        //* Given
+        let content = "\
+key,user_address,query_status,max_budget,subgraphs,domains
+# comment
+0123456789abcdef0123456789abcdef,0x0000000000000000000000000000000000000001
+
+fedcba9876543210fedcba9876543210,0x0000000000000000000000000000000000000002,SERVICE_SHUTOFF,0.001,,example.com *.example.org
+";
+
+        //* When
+        let api_keys = parse_csv(content);
+        let invalid = parse_csv("0123456789abcdef0123456789abcdef,0x01,UNKNOWN");
+        let extra_fields =
+            parse_csv("0123456789abcdef0123456789abcdef,0x01,,,,example.com,mainnet");
+
+        //* Then
+        assert_matches!(api_keys, Ok(api_keys) => {
+            assert_eq!(api_keys.len(), 2);
+            assert_eq!(api_keys[0].key, "0123456789abcdef0123456789abcdef");
+            assert_matches!(api_keys[0].query_status, QueryStatus::Active);
+            assert_eq!(api_keys[0].max_budget_usd, None);
+            assert_matches!(api_keys[1].query_status, QueryStatus::ServiceShutoff);
+            assert_eq!(api_keys[1].max_budget_usd.map(|b| *b), Some(0.001));
+            assert_eq!(api_keys[1].domains, vec!["example.com", "*.example.org"]);
+        });
+        assert!(invalid.is_err());
+        assert!(extra_fields.is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! Token bucket rate limiting of the client queries, per API key and per user address.
+//!
+//! A full token bucket is equivalent to a missing one. So the full buckets, i.e. the ones idle for
+//! long enough, are evicted once the number of buckets reaches [`MIN_SWEEP_THRESHOLD`]. This
+//! bounds the memory used by the buckets of short-lived API keys, e.g. JWT subjects.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+/// Minimum number of buckets, per map, above which the full buckets are evicted.
+const MIN_SWEEP_THRESHOLD: usize = 10_000;
+
+/// Token bucket rate limit.
+///
+/// The number of queries per second must be positive and finite, and the burst at least 1.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "RateLimitConfig")]
+pub struct RateLimit {
+    /// Sustained number of queries per second.
+    pub queries_per_second: f64,
+    /// Maximum number of queries allowed in a burst, i.e. the token bucket capacity.
+    pub burst: u32,
+}
+
+#[derive(Deserialize)]
+struct RateLimitConfig {
+    queries_per_second: f64,
+    burst: u32,
+}
+
+impl TryFrom<RateLimitConfig> for RateLimit {
+    type Error = &'static str;
+
+    fn try_from(conf: RateLimitConfig) -> Result<Self, Self::Error> {
+        if !(conf.queries_per_second.is_finite() && (conf.queries_per_second > 0.0)) {
+            return Err("rate limit queries_per_second must be positive");
+        }
+        if conf.burst < 1 {
+            return Err("rate limit burst must be at least 1");
+        }
+        Ok(Self {
+            queries_per_second: conf.queries_per_second,
+            burst: conf.burst,
+        })
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// The rate limit of the last refill
+    limit: RateLimit,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst.max(1) as f64,
+            updated_at: now,
+            limit: *limit,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        self.tokens = self.tokens_at(limit, now);
+        self.updated_at = now;
+        self.limit = *limit;
+    }
+
+    fn tokens_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + (elapsed * limit.queries_per_second)).min(limit.burst.max(1) as f64)
+    }
+
+    /// Returns true if the bucket is full at the given time, with the rate limit of its last
+    /// refill.
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(&self.limit, now) >= self.limit.burst.max(1) as f64
+    }
+
+    /// Returns the time until a token is available.
+    fn wait_time(&self, limit: &RateLimit) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        // Saturate on overflow, e.g. with a tiny number of queries per second
+        let wait_secs = (1.0 - self.tokens) / limit.queries_per_second;
+        Some(Duration::try_from_secs_f64(wait_secs).unwrap_or(Duration::MAX))
+    }
+}
+
+/// Token buckets, keyed by API key or user address.
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    /// Number of buckets at which the full buckets are evicted on the next insert
+    sweep_threshold: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            sweep_threshold: MIN_SWEEP_THRESHOLD,
+        }
+    }
+}
+
+impl Buckets {
+    /// Returns the key's bucket, inserting a full one if missing.
+    ///
+    /// Inserting a bucket past the sweep threshold evicts the full buckets first. The threshold is
+    /// then set to twice the number of remaining buckets, so that the cost of the evictions stays
+    /// proportional to the number of inserts.
+    fn get_or_insert(&mut self, key: &str, limit: &RateLimit, now: Instant) -> &mut TokenBucket {
+        if !self.buckets.contains_key(key) && (self.buckets.len() >= self.sweep_threshold) {
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+            self.sweep_threshold = (self.buckets.len() * 2).max(MIN_SWEEP_THRESHOLD);
+        }
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+    }
+}
+
+/// Rate limiter of the client queries.
+///
+/// Each API key has its own token bucket, with the key's rate limit or the default one. Each user
+/// address also has a token bucket, shared by all its API keys.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    default_api_key_limit: Option<RateLimit>,
+    user_limit: Option<RateLimit>,
+    api_key_buckets: Arc<Mutex<Buckets>>,
+    user_buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(default_api_key_limit: Option<RateLimit>, user_limit: Option<RateLimit>) -> Self {
+        Self {
+            default_api_key_limit,
+            user_limit,
+            api_key_buckets: Default::default(),
+            user_buckets: Default::default(),
+        }
+    }
+
+    /// Take a token from the API key's and the user's buckets.
+    ///
+    /// If either bucket is empty, no token is taken, and the time to wait before retrying is
+    /// returned.
+    pub fn check(
+        &self,
+        api_key: &str,
+        api_key_limit: Option<RateLimit>,
+        user: &str,
+    ) -> Result<(), Duration> {
+        self.check_at(api_key, api_key_limit, user, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        api_key: &str,
+        api_key_limit: Option<RateLimit>,
+        user: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let api_key_limit = api_key_limit.or(self.default_api_key_limit);
+        let user_limit = self.user_limit.filter(|_| !user.is_empty());
+
+        let mut api_key_buckets = self.api_key_buckets.lock();
+        let mut user_buckets = self.user_buckets.lock();
+        let mut buckets: Vec<(&mut TokenBucket, RateLimit)> = Vec::with_capacity(2);
+        if let Some(limit) = api_key_limit {
+            let bucket = api_key_buckets.get_or_insert(api_key, &limit, now);
+            buckets.push((bucket, limit));
+        }
+        if let Some(limit) = user_limit {
+            let bucket = user_buckets.get_or_insert(user, &limit, now);
+            buckets.push((bucket, limit));
+        }
+
+        let mut wait_time: Option<Duration> = None;
+        for (bucket, limit) in &mut buckets {
+            bucket.refill(limit, now);
+            wait_time = wait_time.max(bucket.wait_time(limit));
+        }
+        if let Some(wait_time) = wait_time {
+            return Err(wait_time);
+        }
+        for (bucket, _) in buckets {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Buckets, RateLimit, RateLimiter};
+
+    const LIMIT: RateLimit = RateLimit {
+        queries_per_second: 2.0,
+        burst: 3,
+    };
+
+    #[test]
+    fn limit_api_key_bursts() {
+        //* Given
+        let rate_limiter = RateLimiter::new(Some(LIMIT), None);
+        let now = Instant::now();
+
+        //* When
+        let burst: Vec<_> = (0..4)
+            .map(|_| rate_limiter.check_at("key", None, "", now))
+            .collect();
+        let later = rate_limiter.check_at("key", None, "", now + Duration::from_millis(500));
+        let other_key = rate_limiter.check_at("other", None, "", now);
+
+        //* Then
+        assert!(burst[..3].iter().all(Result::is_ok));
+        assert_eq!(burst[3], Err(Duration::from_millis(500)));
+        assert!(later.is_ok());
+        assert!(other_key.is_ok());
+    }
+
+    #[test]
+    fn limit_users_across_api_keys() {
+        //* Given
+        let rate_limiter = RateLimiter::new(None, Some(LIMIT));
+        let unlimited = RateLimit {
+            queries_per_second: 1e3,
+            burst: 1000,
+        };
+        let now = Instant::now();
+
+        //* When
+        let results: Vec<_> = (0..4)
+            .map(|n| rate_limiter.check_at(&format!("key-{n}"), Some(unlimited), "user", now))
+            .collect();
+
+        //* Then
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn evict_full_buckets() {
+        //* Given
+        let mut buckets = Buckets {
+            buckets: Default::default(),
+            sweep_threshold: 2,
+        };
+        let now = Instant::now();
+        buckets.get_or_insert("idle", &LIMIT, now).tokens -= 1.0;
+        buckets.get_or_insert("busy", &LIMIT, now).tokens -= 1.0;
+        // The idle bucket is full again after 500ms
+        let later = now + Duration::from_millis(500);
+        buckets.get_or_insert("busy", &LIMIT, later).tokens -= 1.0;
+
+        //* When
+        buckets.get_or_insert("new", &LIMIT, later);
+
+        //* Then
+        let mut keys: Vec<&str> = buckets.buckets.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["busy", "new"]);
+    }
+
+    #[test]
+    fn validate_rate_limits() {
+        let parse = |json: &str| serde_json::from_str::<RateLimit>(json);
+
+        assert_eq!(
+            parse(r#"{ "queries_per_second": 2.0, "burst": 3 }"#).unwrap(),
+            LIMIT
+        );
+        assert!(parse(r#"{ "queries_per_second": 0.0, "burst": 3 }"#).is_err());
+        assert!(parse(r#"{ "queries_per_second": -1.0, "burst": 3 }"#).is_err());
+        assert!(parse(r#"{ "queries_per_second": 2.0, "burst": 0 }"#).is_err());
+    }
+
+    #[test]
+    fn saturate_wait_time() {
+        //* Given
+        let limit = RateLimit {
+            queries_per_second: 1e-20,
+            burst: 1,
+        };
+        let rate_limiter = RateLimiter::new(Some(limit), None);
+        let now = Instant::now();
+
+        //* When
+        let first = rate_limiter.check_at("key", None, "", now);
+        let second = rate_limiter.check_at("key", None, "", now);
+
+        //* Then
+        assert!(first.is_ok());
+        assert_eq!(second, Err(Duration::MAX));
+    }
+}
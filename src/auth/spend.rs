@@ -0,0 +1,191 @@
+//! Local tracking of the query fees spent by each API key.
+//!
+//! The Studio only marks API keys as `MONTHLY_CAP_REACHED` on its next key refresh, so keys can
+//! overshoot their cap in between. The fees of each query are accumulated per API key in hourly
+//! buckets, and the spend over the sliding period is checked against the key's monthly cap.
+//!
+//! The counters are persisted to a local file, so restarts don't reset them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{fs, time::unix_timestamp};
+
+/// Duration of the spend buckets, in seconds.
+pub const BUCKET_SECS: u64 = 60 * 60;
+
+/// Spend of an API key, in USD, indexed by bucket (hours since Unix epoch).
+type Buckets = BTreeMap<u64, f64>;
+
+/// Tracker of the query fees spent by each API key over a sliding period.
+#[derive(Clone)]
+pub struct SpendTracker {
+    period: Duration,
+    spend: Arc<Mutex<HashMap<String, Buckets>>>,
+}
+
+impl Default for SpendTracker {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30 * 24 * 60 * 60))
+    }
+}
+
+impl SpendTracker {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            spend: Default::default(),
+        }
+    }
+
+    /// Add the query fees, in USD, to the API key's spend.
+    pub fn add(&self, api_key: &str, fees_usd: f64) {
+        self.add_at(api_key, fees_usd, unix_timestamp() / 1_000);
+    }
+
+    /// Returns the API key's spend, in USD, over the sliding period.
+    pub fn spend(&self, api_key: &str) -> f64 {
+        self.spend_at(api_key, unix_timestamp() / 1_000)
+    }
+
+    fn add_at(&self, api_key: &str, fees_usd: f64, timestamp: u64) {
+        if fees_usd <= 0.0 || api_key.is_empty() {
+            return;
+        }
+        let oldest = self.oldest_bucket(timestamp);
+        let mut spend = self.spend.lock();
+        let buckets = spend.entry(api_key.to_string()).or_default();
+        buckets.retain(|bucket, _| *bucket >= oldest);
+        *buckets.entry(timestamp / BUCKET_SECS).or_default() += fees_usd;
+    }
+
+    fn spend_at(&self, api_key: &str, timestamp: u64) -> f64 {
+        let oldest = self.oldest_bucket(timestamp);
+        let spend = self.spend.lock();
+        match spend.get(api_key) {
+            Some(buckets) => buckets.range(oldest..).map(|(_, usd)| usd).sum(),
+            None => 0.0,
+        }
+    }
+
+    /// Returns the oldest bucket within the sliding period ending at the given timestamp.
+    fn oldest_bucket(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_sub(self.period.as_secs()) / BUCKET_SECS + 1
+    }
+
+    /// Drop the buckets outside of the sliding period, and the API keys without any spend left.
+    fn prune(&self, timestamp: u64) {
+        let oldest = self.oldest_bucket(timestamp);
+        let mut spend = self.spend.lock();
+        spend.retain(|_, buckets| {
+            buckets.retain(|bucket, _| *bucket >= oldest);
+            !buckets.is_empty()
+        });
+    }
+
+    /// Write the spend counters to the file.
+    pub async fn store(&self, path: &Path) -> anyhow::Result<()> {
+        self.prune(unix_timestamp() / 1_000);
+        let content = serde_json::to_vec(&PersistedSpend {
+            spend: self.spend.lock().clone(),
+        })?;
+        fs::write_atomic(path.to_path_buf(), content).await
+    }
+
+    /// Load the spend counters from the file, adding them to the current ones.
+    pub fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let data: PersistedSpend =
+            serde_json::from_str(&content).context("invalid persisted API key spend")?;
+        let mut spend = self.spend.lock();
+        for (api_key, buckets) in data.spend {
+            let entry = spend.entry(api_key).or_default();
+            for (bucket, usd) in buckets {
+                *entry.entry(bucket).or_default() += usd;
+            }
+        }
+        drop(spend);
+        self.prune(unix_timestamp() / 1_000);
+        Ok(())
+    }
+
+    /// Load the persisted spend counters, if any, and spawn a task persisting them periodically.
+    pub fn persist(&self, path: PathBuf, interval: Duration) {
+        if path.exists() {
+            if let Err(load_spend_err) = self.load(&path) {
+                tracing::error!(%load_spend_err);
+            }
+        }
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(store_spend_err) = tracker.store(&path).await {
+                    tracing::error!(%store_spend_err);
+                }
+            }
+        });
+    }
+}
+
+/// The persisted spend counters.
+#[derive(Deserialize, Serialize)]
+struct PersistedSpend {
+    spend: HashMap<String, Buckets>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{SpendTracker, BUCKET_SECS};
+    use crate::fs::temp_path;
+
+    #[test]
+    fn sliding_period_spend() {
+        //* Given
+        let tracker = SpendTracker::new(Duration::from_secs(24 * BUCKET_SECS));
+        let start = 1_700_000_000 / BUCKET_SECS * BUCKET_SECS;
+
+        //* When
+        tracker.add_at("key", 1.0, start);
+        tracker.add_at("key", 2.0, start + 12 * BUCKET_SECS);
+        tracker.add_at("other", 4.0, start);
+
+        //* Then
+        assert_eq!(tracker.spend_at("key", start + 12 * BUCKET_SECS), 3.0);
+        assert_eq!(tracker.spend_at("key", start + 24 * BUCKET_SECS), 2.0);
+        assert_eq!(tracker.spend_at("key", start + 36 * BUCKET_SECS), 0.0);
+        assert_eq!(tracker.spend_at("other", start), 4.0);
+        assert_eq!(tracker.spend_at("unknown", start), 0.0);
+    }
+
+    #[tokio::test]
+    async fn persist_spend() {
+        //* Given
+        let path = temp_path("spend.json");
+        let tracker = SpendTracker::default();
+        tracker.add("key", 1.5);
+
+        //* When
+        tracker.store(&path).await.expect("store spend");
+        let restored = SpendTracker::default();
+        let result = restored.load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        //* Then
+        assert!(result.is_ok());
+        assert_eq!(restored.spend("key"), 1.5);
+    }
+}
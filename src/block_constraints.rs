@@ -363,6 +363,7 @@ mod tests {
         let examples = [
             "{ __schema { queryType { name } } }",
             "{ __type(name:\"Droid\") { name description } }",
+            "{ __typename }",
         ];
         for example in examples {
             let context = Context::new(example, "").unwrap();
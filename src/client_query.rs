@@ -17,7 +17,7 @@ use headers::ContentType;
 use indexer_selection::{ArrayVec, Candidate, Normalized};
 use ordered_float::NotNan;
 use prost::bytes::Buf;
-use rand::{thread_rng, Rng as _};
+use rand::{seq::SliceRandom as _, thread_rng, Rng};
 use serde::Deserialize;
 use serde_json::value::RawValue;
 use thegraph_core::{alloy::primitives::BlockNumber, AllocationId, DeploymentId, IndexerId};
@@ -36,7 +36,11 @@ use crate::{
     indexing_performance,
     metrics::{with_metric, METRICS},
     middleware::RequestId,
-    network::{self, DeploymentError, Indexing, IndexingId, ResolvedSubgraphInfo, SubgraphError},
+    network::{
+        self, DeploymentError, Indexing, IndexingAllocation, IndexingId, ResolvedSubgraphInfo,
+        SubgraphError,
+    },
+    query_features,
     receipts::ReceiptStatus,
     reports,
 };
@@ -136,10 +140,22 @@ async fn resolve_subgraph_info(
     ctx: &Context,
     auth: &AuthSettings,
     selector: QuerySelector,
+) -> Result<ResolvedSubgraphInfo, Error> {
+    let info = resolve_authorized_selector(ctx, auth, selector)?;
+    authorize_resolved_subgraph_info(auth, info)
+}
+
+fn resolve_authorized_selector(
+    ctx: &Context,
+    auth: &AuthSettings,
+    selector: QuerySelector,
 ) -> Result<ResolvedSubgraphInfo, Error> {
     match selector {
         QuerySelector::Subgraph(ref id) => {
-            // If the subgraph is not authorized, return an error.
+            // If the subgraph is denied or not authorized, return an error.
+            if auth.is_subgraph_denied(id) {
+                return Err(Error::Auth(anyhow!("subgraph denied by user")));
+            }
             if !auth.is_subgraph_authorized(id) {
                 return Err(Error::Auth(anyhow!("subgraph not authorized by user")));
             }
@@ -157,8 +173,13 @@ async fn resolve_subgraph_info(
             }
         }
         QuerySelector::Deployment(ref id) => {
-            // Authorization is based on the "authorized subgraphs" allowlist. We need to resolve
-            // the subgraph deployments to check if any of the deployment's subgraphs are authorized
+            if auth.is_deployment_denied(id) {
+                return Err(Error::Auth(anyhow!("deployment denied by user")));
+            }
+
+            // Authorization is based on the "authorized deployments" and "authorized subgraphs"
+            // allowlists. We need to resolve the subgraph deployments to check if any of the
+            // deployment's subgraphs are authorized
             match ctx.network.resolve_with_deployment_id(id) {
                 Err(DeploymentError::NoAllocations) => {
                     Err(Error::SubgraphNotFound(anyhow!("no allocations",)))
@@ -166,7 +187,9 @@ async fn resolve_subgraph_info(
                 Ok(None) => Err(Error::SubgraphNotFound(anyhow!("{selector}",))),
                 Ok(Some(info)) if info.indexings.is_empty() => Err(Error::NoIndexers),
                 Ok(Some(info)) => {
-                    if !auth.is_any_deployment_subgraph_authorized(&info.subgraphs) {
+                    if info.subgraphs.iter().any(|s| auth.is_subgraph_denied(s)) {
+                        Err(Error::Auth(anyhow!("deployment subgraph denied by user")))
+                    } else if !auth.is_deployment_authorized(id, &info.subgraphs) {
                         Err(Error::Auth(anyhow!("deployment not authorized by user")))
                     } else {
                         Ok(info)
@@ -177,6 +200,35 @@ async fn resolve_subgraph_info(
     }
 }
 
+/// Check the resolved subgraph info against the chain allowlist, and drop the denied deployments
+/// from the subgraph versions.
+fn authorize_resolved_subgraph_info(
+    auth: &AuthSettings,
+    mut info: ResolvedSubgraphInfo,
+) -> Result<ResolvedSubgraphInfo, Error> {
+    if !auth.is_chain_authorized(&info.chain) {
+        return Err(Error::Auth(anyhow!(
+            "chain not authorized by user: {}",
+            info.chain
+        )));
+    }
+
+    if !auth.denied_deployments.is_empty() {
+        info.versions
+            .retain(|deployment| !auth.is_deployment_denied(deployment));
+        if info.versions.is_empty() {
+            return Err(Error::Auth(anyhow!("all subgraph versions denied by user")));
+        }
+        info.indexings
+            .retain(|id, _| !auth.is_deployment_denied(&id.deployment));
+        if info.indexings.is_empty() {
+            return Err(Error::NoIndexers);
+        }
+    }
+
+    Ok(info)
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn run_indexer_queries(
     ctx: Context,
@@ -200,7 +252,7 @@ async fn run_indexer_queries(
     // We handle these errors here, instead of `handle_query`, because the agora context is tied to
     // the lifetime of the query body which may need to extend past the client response. Even if
     // it doesn't, it is relatively difficult to convince the compiler of that.
-    let agora_context = match AgoraContext::new(&client_request.query, &variables) {
+    let mut agora_context = match AgoraContext::new(&client_request.query, &variables) {
         Ok(agora_context) => agora_context,
         Err(err) => {
             client_response
@@ -210,6 +262,12 @@ async fn run_indexer_queries(
         }
     };
 
+    // Check the query against the API key query policy, before selecting any candidate
+    if let Err(err) = auth.query_policy.check_query(&agora_context) {
+        client_response.try_send(Err(Error::BadQuery(err))).unwrap();
+        return;
+    }
+
     // Get the chain information for the resolved subgraph
     let chain = ctx.chains.chain(&subgraph.chain);
     let (chain_head, blocks_per_minute, block_requirements) = {
@@ -247,6 +305,7 @@ async fn run_indexer_queries(
     // Candidate selection preparation
     let (mut candidates, errors) = build_candidates_list(
         &ctx,
+        &mut agora_context,
         budget,
         chain_head,
         blocks_per_minute,
@@ -286,7 +345,8 @@ async fn run_indexer_queries(
         for &selection in &selections {
             let indexer = selection.id;
             let deployment = selection.data.deployment;
-            let largest_allocation = selection.data.largest_allocation;
+            let allocation = select_allocation(&selection.data.allocations, &mut thread_rng())
+                .unwrap_or(selection.data.largest_allocation);
             let url = selection.data.url.clone();
             let seconds_behind = selection.seconds_behind;
             let legacy_scalar = !selection.data.tap_support;
@@ -297,10 +357,9 @@ async fn run_indexer_queries(
             let indexer_fee = selection.fee.as_f64() * budget as f64;
             let fee = indexer_fee.max(min_fee) as u128;
             let receipt = match if legacy_scalar {
-                ctx.receipt_signer
-                    .create_legacy_receipt(largest_allocation, fee)
+                ctx.receipt_signer.create_legacy_receipt(allocation, fee)
             } else {
-                ctx.receipt_signer.create_receipt(largest_allocation, fee)
+                ctx.receipt_signer.create_receipt(allocation, fee)
             } {
                 Ok(receipt) => receipt,
                 Err(err) => {
@@ -328,7 +387,7 @@ async fn run_indexer_queries(
                     let report = reports::IndexerRequest {
                         indexer,
                         deployment,
-                        largest_allocation,
+                        allocation,
                         url: url.to_string(),
                         receipt,
                         subgraph_chain,
@@ -348,12 +407,21 @@ async fn run_indexer_queries(
         while let Some(report) = rx.recv().await {
             match report.result.as_ref() {
                 Ok(response) if client_response_time.is_none() => {
-                    let _ = client_response.try_send(Ok(response.clone()));
+                    let _ = client_response.try_send(
+                        match auth.query_policy.check_response(&response.client_response) {
+                            Ok(()) => Ok(response.clone()),
+                            Err(err) => Err(Error::BadQuery(err)),
+                        },
+                    );
                     client_response_time = Some(start_time.elapsed());
                     client_response_bytes = Some(response.client_response.len() as u32);
                 }
                 Ok(_) => (),
                 Err(err) => {
+                    if let IndexerError::AllocationClosed(_) = err {
+                        ctx.network
+                            .report_closed_allocation(report.indexer, report.allocation);
+                    }
                     indexer_errors.insert(report.indexer, err.clone());
                 }
             }
@@ -363,11 +431,8 @@ async fn run_indexer_queries(
                 Err(IndexerError::Timeout) => ReceiptStatus::Unknown,
                 Err(_) => ReceiptStatus::Failure,
             };
-            ctx.receipt_signer.record_receipt(
-                &report.largest_allocation,
-                &report.receipt,
-                receipt_status,
-            );
+            ctx.receipt_signer
+                .record_receipt(&report.allocation, &report.receipt, receipt_status);
 
             indexer_requests.push(report);
         }
@@ -391,10 +456,14 @@ async fn run_indexer_queries(
         }
     };
 
-    let result = if indexer_requests.iter().any(|r| r.result.is_ok()) {
-        Ok(())
-    } else {
-        Err(Error::BadIndexers(indexer_errors))
+    // The client response is the first successful indexer response, unless rejected by the query
+    // policy of the API key.
+    let result = match indexer_requests.iter().find_map(|r| r.result.as_ref().ok()) {
+        Some(response) => auth
+            .query_policy
+            .check_response(&response.client_response)
+            .map_err(Error::BadQuery),
+        None => Err(Error::BadIndexers(indexer_errors)),
     };
 
     let total_fees_grt: f64 = indexer_requests
@@ -403,6 +472,7 @@ async fn run_indexer_queries(
         .sum();
     let total_fees_usd = USD(NotNan::new(total_fees_grt / *grt_per_usd).unwrap());
     let _ = ctx.budgeter.feedback.send(total_fees_usd);
+    ctx.spend_tracker.add(&auth.key, *total_fees_usd.0);
 
     for indexer_request in &indexer_requests {
         let latest_block = match &indexer_request.result {
@@ -485,13 +555,42 @@ struct CandidateMetadata {
     #[debug(with = std::fmt::Display::fmt)]
     url: Url,
     largest_allocation: AllocationId,
+    allocations: Vec<IndexingAllocation>,
     tap_support: bool,
 }
 
+/// Select the allocation to issue a receipt against, at random, weighted by the allocated tokens.
+///
+/// This spreads the query fees across all the indexer's allocations on the deployment, in
+/// proportion to their stake. If no tokens are allocated, the allocations are equally likely.
+fn select_allocation(
+    allocations: &[IndexingAllocation],
+    rng: &mut impl Rng,
+) -> Option<AllocationId> {
+    let total_tokens = allocations
+        .iter()
+        .fold(0_u128, |sum, a| sum.saturating_add(a.allocated_tokens));
+    if total_tokens == 0 {
+        return allocations.choose(rng).map(|a| a.id);
+    }
+    let mut target = rng.gen_range(0..total_tokens);
+    for allocation in allocations {
+        if target < allocation.allocated_tokens {
+            return Some(allocation.id);
+        }
+        target -= allocation.allocated_tokens;
+    }
+    allocations.last().map(|a| a.id)
+}
+
 /// Given a list of indexings, build a list of candidates that are within the required block range
 /// and have the required performance.
+///
+/// The candidates' fees are computed by evaluating the indexers' cost models against the query.
+#[allow(clippy::too_many_arguments)]
 fn build_candidates_list(
     ctx: &Context,
+    agora_context: &mut AgoraContext<'_>,
     budget: u128,
     chain_head: BlockNumber,
     blocks_per_minute: u64,
@@ -520,6 +619,9 @@ fn build_candidates_list(
     // Lock the indexing performance and get access to the latest performance snapshots
     let perf_snapshots = ctx.indexing_perf.latest();
 
+    // The minimum graph-node version required by the features used by the query
+    let required_graph_node_version = query_features::required_graph_node_version(agora_context);
+
     for (indexing_id, indexing) in indexings {
         // If the indexer is not available, register an error and continue to the next indexer
         let indexing = match indexing {
@@ -534,6 +636,33 @@ fn build_candidates_list(
             continue;
         }
 
+        // If the indexer failed the latest health probes, register an error and continue to the
+        // next indexer
+        if ctx.indexer_health.is_unreachable(&indexing_id.indexer) {
+            candidates_errors.insert(
+                indexing_id.indexer,
+                IndexerError::Unavailable(UnavailableReason::NoStatus(
+                    "health probe failed".to_string(),
+                )),
+            );
+            continue;
+        }
+
+        // If the indexer's graph-node version does not support the query features, register an
+        // error and continue to the next indexer
+        if let Some(required) = required_graph_node_version {
+            if &indexing.indexer.graph_node_version < required {
+                candidates_errors.insert(
+                    indexing_id.indexer,
+                    IndexerError::Unavailable(UnavailableReason::NotSupported(format!(
+                        "graph-node version {} below {required}, required by the query",
+                        indexing.indexer.graph_node_version,
+                    ))),
+                );
+                continue;
+            }
+        }
+
         // Get the performance snapshot for the indexer and calculate the expected performance.
         // If the indexer is not available, register an error and continue to the next indexer
         let perf = match perf_snapshots
@@ -588,16 +717,18 @@ fn build_candidates_list(
             }
         }
 
+        let fee = indexing.cost_model.fee(agora_context);
         candidates_list.push(Candidate {
             id: indexing_id.indexer,
             data: CandidateMetadata {
                 deployment,
                 url: indexing.indexer.url.clone(),
                 largest_allocation: indexing.largest_allocation,
+                allocations: indexing.allocations.clone(),
                 tap_support: indexing.indexer.tap_support,
             },
             perf: perf.response,
-            fee: Normalized::new(indexing.fee as f64 / budget as f64).unwrap_or(Normalized::ONE),
+            fee: Normalized::new(fee as f64 / budget as f64).unwrap_or(Normalized::ONE),
             seconds_behind: perf.seconds_behind,
             slashable_grt: (indexing.indexer.staked_tokens as f64 * 1e-18) as u64,
             zero_allocation: indexing.total_allocated_tokens == 0,
@@ -709,6 +840,24 @@ pub async fn handle_indexer_query(
     };
     let subgraph =
         resolve_subgraph_info(&ctx, &auth, QuerySelector::Deployment(deployment)).await?;
+
+    // Check the query against the API key query policy. The payload is only parsed if the policy
+    // restricts queries, since it is otherwise forwarded as-is.
+    if auth.query_policy.restricts_query() {
+        let client_request: QueryBody =
+            serde_json::from_str(&payload).map_err(|err| Error::BadQuery(err.into()))?;
+        let variables = client_request
+            .variables
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let agora_context = AgoraContext::new(&client_request.query, &variables)
+            .map_err(|err| Error::BadQuery(anyhow!("{err}")))?;
+        auth.query_policy
+            .check_query(&agora_context)
+            .map_err(Error::BadQuery)?;
+    }
+
     let indexing = subgraph
         .indexings
         .get(&indexing_id)
@@ -732,7 +881,8 @@ pub async fn handle_indexer_query(
     let one_grt = NotNan::new(1e18).unwrap();
     let fee = *(ctx.budgeter.query_fees_target.0 * grt_per_usd * one_grt) as u128;
 
-    let allocation = indexing.largest_allocation;
+    let allocation = select_allocation(&indexing.allocations, &mut thread_rng())
+        .unwrap_or(indexing.largest_allocation);
     let receipt = match if indexing.indexer.tap_support {
         ctx.receipt_signer.create_receipt(allocation, fee)
     } else {
@@ -758,11 +908,15 @@ pub async fn handle_indexer_query(
         .query_indexer(deployment_url, indexer_auth, &payload)
         .in_current_span()
         .await;
+    if let Err(IndexerError::AllocationClosed(_)) = &result {
+        ctx.network
+            .report_closed_allocation(indexing_id.indexer, allocation);
+    }
     let response_time_ms = start_time.elapsed().as_millis() as u16;
     let indexer_request = reports::IndexerRequest {
         indexer: indexing_id.indexer,
         deployment: indexing_id.deployment,
-        largest_allocation: allocation,
+        allocation,
         url: indexing.indexer.url.to_string(),
         receipt,
         subgraph_chain: subgraph.chain,
@@ -774,10 +928,18 @@ pub async fn handle_indexer_query(
     };
 
     let report_result = match &result {
-        Ok(_) => Ok(()),
+        Ok(response) => auth
+            .query_policy
+            .check_response(&response.client_response)
+            .map_err(Error::BadQuery),
         Err(err) => Err(bad_indexers(err.clone())),
     };
-    let result = result.map_err(bad_indexers);
+    let result = result.map_err(bad_indexers).and_then(|response| {
+        auth.query_policy
+            .check_response(&response.client_response)
+            .map_err(Error::BadQuery)?;
+        Ok(response)
+    });
 
     let deployment = indexing_id.deployment.to_string();
     let indexer = format!("{:?}", indexing_id.indexer);
@@ -808,6 +970,10 @@ pub async fn handle_indexer_query(
         fee = indexer_request.receipt.grt_value() as f64 * 1e-18,
         "indexer_request"
     );
+    ctx.spend_tracker.add(
+        &auth.key,
+        indexer_request.receipt.grt_value() as f64 * 1e-18 / *grt_per_usd,
+    );
 
     ctx.indexing_perf.feedback(
         indexer_request.indexer,
@@ -874,6 +1040,11 @@ mod tests {
                 payment_required: false,
                 api_keys: watch::channel(Default::default()).1,
                 special_api_keys: Default::default(),
+                rate_limiter: Default::default(),
+                spend_tracker: Default::default(),
+                jwt_issuers: Default::default(),
+                jwt_max_lifetime: Default::default(),
+                trusted_proxy_hops: 0,
             };
             if let Some(key) = key {
                 ctx.api_keys = watch::channel(HashMap::from([(
@@ -1040,4 +1211,46 @@ mod tests {
             });
         }
     }
+
+    mod select_allocation {
+        use rand::{rngs::SmallRng, SeedableRng};
+        use thegraph_core::{alloy::primitives::Address, AllocationId};
+
+        use crate::{client_query::select_allocation, network::IndexingAllocation};
+
+        fn allocation(n: u8, allocated_tokens: u128) -> IndexingAllocation {
+            IndexingAllocation {
+                id: AllocationId::from(Address::repeat_byte(n)),
+                allocated_tokens,
+            }
+        }
+
+        #[test]
+        fn select_allocations_weighted_by_stake() {
+            //* Given
+            let mut rng = SmallRng::seed_from_u64(0);
+            let allocations = [allocation(1, 100), allocation(2, 300), allocation(3, 0)];
+
+            //* When
+            let mut counts = [0; 3];
+            for _ in 0..10_000 {
+                let selected = select_allocation(&allocations, &mut rng).unwrap();
+                let index = allocations.iter().position(|a| a.id == selected).unwrap();
+                counts[index] += 1;
+            }
+
+            //* Then
+            assert!((2_000..3_000).contains(&counts[0]), "{counts:?}");
+            assert!((7_000..8_000).contains(&counts[1]), "{counts:?}");
+            assert_eq!(counts[2], 0);
+        }
+
+        #[test]
+        fn select_allocations_without_stake() {
+            let mut rng = SmallRng::seed_from_u64(0);
+            let allocations = [allocation(1, 0), allocation(2, 0)];
+            assert!(select_allocation(&allocations, &mut rng).is_some());
+            assert_eq!(select_allocation(&[], &mut rng), None);
+        }
+    }
 }
@@ -3,9 +3,9 @@ use thegraph_core::alloy::dyn_abi::Eip712Domain;
 use tokio::sync::{mpsc, watch};
 
 use crate::{
-    budgets::Budgeter, chains::Chains, indexer_client::IndexerClient,
-    indexing_performance::IndexingPerformance, network::NetworkService, receipts::ReceiptSigner,
-    reports,
+    auth::SpendTracker, budgets::Budgeter, chains::Chains, indexer_client::IndexerClient,
+    indexer_health::IndexerHealth, indexing_performance::IndexingPerformance,
+    network::NetworkService, receipts::ReceiptSigner, reports,
 };
 
 #[derive(Clone)]
@@ -17,6 +17,8 @@ pub struct Context {
     pub chains: &'static Chains,
     pub network: NetworkService,
     pub indexing_perf: IndexingPerformance,
+    pub indexer_health: IndexerHealth,
     pub attestation_domain: &'static Eip712Domain,
     pub reporter: mpsc::UnboundedSender<reports::ClientRequest>,
+    pub spend_tracker: SpendTracker,
 }
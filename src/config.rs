@@ -1,8 +1,10 @@
 //! The Graph Gateway configuration.
 
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::BTreeMap,
+    fmt,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::Context;
@@ -10,23 +12,31 @@ use ipnetwork::IpNetwork;
 use ordered_float::NotNan;
 use semver::Version;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
+use serde_with::{serde_as, DisplayFromStr, DurationSeconds};
 use thegraph_core::{
     alloy::primitives::{Address, BlockNumber, B256, U256},
     DeploymentId,
 };
 use url::Url;
 
-use crate::{auth::APIKey, network::subgraph_client::TrustedIndexer};
+use crate::{
+    auth::{APIKey, RateLimit, SPEND_BUCKET_SECS},
+    network::subgraph_client::TrustedIndexer,
+};
 
 /// The Graph Gateway configuration.
 #[serde_as]
 #[derive(Deserialize)]
 pub struct Config {
+    /// Bearer auth token of the admin webhook pushing API key updates and revocations. The webhook
+    /// is disabled if not set.
+    #[serde(default)]
+    pub admin_auth_token: Option<String>,
     #[serde(default)]
     pub api_keys: Option<ApiKeys>,
     pub attestations: AttestationConfig,
-    /// List of indexer addresses to block. This should only be used temprorarily.
+    /// List of indexer addresses to block. This should only be used temprorarily, so entries
+    /// should set an `until` expiry timestamp.
     #[serde(default)]
     pub blocked_indexers: BTreeMap<Address, BlockedIndexer>,
     /// Chain aliases
@@ -36,8 +46,22 @@ pub struct Config {
     pub exchange_rate_provider: ExchangeRateProvider,
     /// Graph network environment identifier, inserted into Kafka messages
     pub graph_env_id: String,
-    /// File path of CSV containing rows of `IpNetwork,Country`
+    /// File path of CSV containing rows of `IpNetwork,Country`, with an optional trailing `,ASN`
     pub ip_blocker_db: Option<PathBuf>,
+    /// Rules applied, in order, to indexer hosts found in the IP blocker DB. The first matching
+    /// rule decides if the indexer is blocked. Hosts not matching any rule are allowed. Defaults
+    /// to blocking all hosts found in the IP blocker DB.
+    #[serde(default)]
+    pub ip_blocker_rules: Vec<HostPolicyRule>,
+    /// Trusted JWT issuers. Clients may authenticate with a JWT signed by one of these issuers
+    /// instead of an API key.
+    #[serde(default)]
+    pub jwt_issuers: Vec<JwtIssuer>,
+    /// Maximum lifetime of the accepted JWTs, from their `iat` claim (or the current time if not
+    /// set) to their `exp` claim. Defaults to 1 day.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_jwt_max_lifetime")]
+    pub jwt_max_lifetime: Duration,
     /// See https://github.com/confluentinc/librdkafka/blob/master/CONFIGURATION.md
     #[serde(default)]
     pub kafka: KafkaConfig,
@@ -49,13 +73,29 @@ pub struct Config {
     /// Minimum indexer-service version that will receive queries
     #[serde_as(as = "DisplayFromStr")]
     pub min_indexer_version: Version,
-    /// Indexers used to query the network subgraph
+    /// Network topology update and indexer information resolution settings
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// Number of trusted proxies in front of the gateway, appending the client IP to the
+    /// `X-Forwarded-For` header. Used to check the `allowed_ips` of API keys. Defaults to 0, using
+    /// the connecting address.
+    #[serde(default)]
+    pub trusted_proxy_hops: usize,
+    /// Indexers used to query the network subgraph. Not required when `static_topology` is set.
+    #[serde(default)]
     pub trusted_indexers: Vec<TrustedIndexer>,
+    /// Minimum number of trusted indexers that must return the same network subgraph data, at the
+    /// same block hash, for it to be accepted. Defaults to 1, trusting the first response.
+    #[serde(default)]
+    pub trusted_indexers_quorum: Option<usize>,
     /// Check payment state of client (disable for testnets)
     pub payment_required: bool,
     /// POI blocklist
     #[serde(default)]
     pub poi_blocklist: Vec<BlockedPoi>,
+    /// Automatic POI divergence detection. Disabled if not set.
+    #[serde(default)]
+    pub poi_divergence: Option<PoiDivergenceConfig>,
     /// public API port
     pub port_api: u16,
     /// private metrics port
@@ -63,7 +103,25 @@ pub struct Config {
     /// Target for indexer fees paid per request
     #[serde(deserialize_with = "deserialize_not_nan_f64")]
     pub query_fees_target: NotNan<f64>,
+    /// Default rate limit of each API key, for the API keys without their own rate limit. Not
+    /// rate limited if not set.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
     pub receipts: Receipts,
+    /// Local tracking of the query fees spent by each API key, checked against their
+    /// `monthly_cap_usd`
+    #[serde(default)]
+    pub spend_tracking: SpendTrackingConfig,
+    /// File path of a JSON static network topology, used instead of the network subgraph. See
+    /// [`static_topology`](crate::network::static_topology).
+    #[serde(default)]
+    pub static_topology: Option<PathBuf>,
+    /// Persisted network topology, used on cold starts when the network subgraph is unavailable
+    #[serde(default)]
+    pub topology_snapshot: Option<TopologySnapshotConfig>,
+    /// Rate limit of each user address, shared by all its API keys. Not rate limited if not set.
+    #[serde(default)]
+    pub user_rate_limit: Option<RateLimit>,
 }
 
 /// Deserialize a `NotNan<f64>` from a `f64` and return an error if the value is NaN.
@@ -75,6 +133,23 @@ where
     NotNan::new(value).map_err(serde::de::Error::custom)
 }
 
+fn default_jwt_max_lifetime() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+/// A trusted JWT issuer.
+///
+/// See [`Config`]'s [`jwt_issuers`](struct.Config.html#structfield.jwt_issuers).
+#[derive(Clone, Deserialize)]
+pub struct JwtIssuer {
+    /// Value of the `iss` claim of the tokens minted by this issuer
+    pub iss: String,
+    /// Signature algorithm of the tokens, e.g. `ES256` or `EdDSA`
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// PEM encoded public key of the issuer
+    pub public_key: String,
+}
+
 /// API keys configuration.
 ///
 /// See [`Config`]'s [`api_keys`](struct.Config.html#structfield.api_keys).
@@ -91,16 +166,116 @@ pub enum ApiKeys {
         /// API keys that won't be blocked for non-payment
         #[serde(default)]
         special: Vec<String>,
+        /// File path where the last fetched API keys are persisted. They are used on startup if
+        /// the endpoint is unavailable.
+        #[serde(default)]
+        cache: Option<PathBuf>,
+    },
+    /// API keys loaded from a JSON or CSV file, reloaded when the file is modified. See
+    /// [`key_file`](crate::auth::key_file).
+    File {
+        /// File path of the API keys
+        path: PathBuf,
+        /// API keys that won't be blocked for non-payment
+        #[serde(default)]
+        special: Vec<String>,
     },
     /// Fixed set of API keys
     Fixed(Vec<APIKey>),
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct BlockedIndexer {
     /// empty array blocks on all deployments
-    pub deployments: Vec<DeploymentId>,
+    pub deployments: Vec<BlockedDeployment>,
     pub reason: String,
+    /// Unix timestamp (in seconds) after which the block expires. If not set, the block never
+    /// expires.
+    #[serde(default)]
+    pub until: Option<u64>,
+}
+
+/// A deployment blocked for some indexer.
+///
+/// This can be either a deployment ID, or an object containing the deployment ID and an optional
+/// `until` Unix timestamp (in seconds) after which the block expires.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "BlockedDeploymentConfig")]
+pub struct BlockedDeployment {
+    pub id: DeploymentId,
+    pub until: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockedDeploymentConfig {
+    Id(DeploymentId),
+    WithExpiry {
+        deployment: DeploymentId,
+        #[serde(default)]
+        until: Option<u64>,
+    },
+}
+
+impl From<BlockedDeploymentConfig> for BlockedDeployment {
+    fn from(conf: BlockedDeploymentConfig) -> Self {
+        match conf {
+            BlockedDeploymentConfig::Id(id) => Self { id, until: None },
+            BlockedDeploymentConfig::WithExpiry { deployment, until } => Self {
+                id: deployment,
+                until,
+            },
+        }
+    }
+}
+
+/// Indexer host policy rule.
+///
+/// See [`Config`]'s [`ip_blocker_rules`](struct.Config.html#structfield.ip_blocker_rules).
+#[derive(Clone, Debug, Deserialize)]
+pub struct HostPolicyRule {
+    pub action: HostPolicyAction,
+    /// Countries matched by this rule. If empty, all countries are matched.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// ASNs matched by this rule. If empty, all ASNs are matched.
+    #[serde(default)]
+    pub asns: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostPolicyAction {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for HostPolicyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            HostPolicyAction::Allow => write!(f, "allow")?,
+            HostPolicyAction::Deny => write!(f, "deny")?,
+        };
+        if self.countries.is_empty() && self.asns.is_empty() {
+            return write!(f, " all");
+        }
+        if !self.countries.is_empty() {
+            write!(f, " country={}", self.countries.join("|"))?;
+        }
+        if !self.asns.is_empty() {
+            let asns: Vec<String> = self.asns.iter().map(|asn| format!("AS{asn}")).collect();
+            write!(f, " asn={}", asns.join("|"))?;
+        }
+        Ok(())
+    }
+}
+
+/// An IP network entry of the IP blocker DB.
+#[derive(Clone, Debug)]
+pub struct IpBlockerDbEntry {
+    pub network: IpNetwork,
+    pub country: String,
+    pub asn: Option<u32>,
 }
 
 /// Attestation configuration.
@@ -128,7 +303,7 @@ pub enum ExchangeRateProvider {
 /// Kafka configuration.
 ///
 /// See [`Config`]'s [`kafka`](struct.Config.html#structfield.kafka).
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct KafkaConfig(BTreeMap<String, String>);
 
 impl Default for KafkaConfig {
@@ -173,6 +348,237 @@ pub struct Receipts {
     pub verifier: Address,
 }
 
+/// Network topology update and indexer information resolution settings. All durations are in
+/// seconds.
+///
+/// See [`Config`]'s [`network`](struct.Config.html#structfield.network).
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Interval between network topology updates. Defaults to 60.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub update_interval: Duration,
+    /// Number of entities per network subgraph query page. Defaults to 500.
+    pub subgraph_page_size: usize,
+    /// Interval between full syncs of the network subgraph, in between which only the changed
+    /// entities are fetched. Defaults to 30 minutes.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub subgraph_full_sync_interval: Duration,
+    /// Timeout for resolving the indexers' hosts. Defaults to 5.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub host_resolution_timeout: Duration,
+    /// Minimum time-to-live of the cached host resolutions, overriding shorter DNS record TTLs.
+    /// Defaults to 60.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub host_resolution_min_ttl: Duration,
+    /// Maximum time-to-live of the cached host resolutions, overriding longer DNS record TTLs.
+    /// Defaults to 1 hour.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub host_resolution_max_ttl: Duration,
+    /// Time-to-live of the cached failed host resolutions. Defaults to 30.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub host_resolution_negative_ttl: Duration,
+    /// Timeout for fetching the indexers' versions. Defaults to 5.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub version_timeout: Duration,
+    /// Timeout for fetching the indexers' public POIs. Defaults to 5.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub poi_timeout: Duration,
+    /// Time-to-live of the cached public POIs. Defaults to 20 minutes.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub poi_cache_ttl: Duration,
+    /// Timeout for fetching the indexers' indexing progress. Defaults to 25.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub indexing_progress_timeout: Duration,
+    /// Timeout for fetching the indexers' cost models. Defaults to 5.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub cost_model_timeout: Duration,
+    /// Maximum age of the cached indexer cost models used when fetching them fails. Defaults to
+    /// 10 minutes.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub cost_model_max_age: Duration,
+    /// If set, each indexer's indexing progress timeout is this factor times its observed status
+    /// endpoint latency, bounded by `adaptive_timeout_min` and `indexing_progress_timeout`. Must be
+    /// greater than 1 and at most 100.
+    pub adaptive_timeout_factor: Option<f64>,
+    /// Lower bound of the adaptive indexing progress timeouts. Defaults to 2.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub adaptive_timeout_min: Duration,
+    /// Interval between indexer health probes. Defaults to 10.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub health_probe_interval: Duration,
+    /// Timeout of the indexer health probes. Defaults to 5.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub health_probe_timeout: Duration,
+    /// Number of consecutive failed health probes after which an indexer is considered
+    /// unreachable. Defaults to 3.
+    pub health_probe_failure_threshold: u32,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            update_interval: Duration::from_secs(60),
+            subgraph_page_size: 500,
+            subgraph_full_sync_interval: Duration::from_secs(30 * 60),
+            host_resolution_timeout: Duration::from_secs(5),
+            host_resolution_min_ttl: Duration::from_secs(60),
+            host_resolution_max_ttl: Duration::from_secs(60 * 60),
+            host_resolution_negative_ttl: Duration::from_secs(30),
+            version_timeout: Duration::from_secs(5),
+            poi_timeout: Duration::from_secs(5),
+            poi_cache_ttl: Duration::from_secs(20 * 60),
+            indexing_progress_timeout: Duration::from_secs(25),
+            cost_model_timeout: Duration::from_secs(5),
+            cost_model_max_age: Duration::from_secs(10 * 60),
+            adaptive_timeout_factor: None,
+            adaptive_timeout_min: Duration::from_secs(2),
+            health_probe_interval: Duration::from_secs(10),
+            health_probe_timeout: Duration::from_secs(5),
+            health_probe_failure_threshold: 3,
+        }
+    }
+}
+
+/// Upper bound of [`NetworkConfig::adaptive_timeout_factor`].
+const MAX_ADAPTIVE_TIMEOUT_FACTOR: f64 = 100.0;
+
+impl NetworkConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        let timeouts = [
+            ("host_resolution_timeout", self.host_resolution_timeout),
+            ("version_timeout", self.version_timeout),
+            ("poi_timeout", self.poi_timeout),
+            ("indexing_progress_timeout", self.indexing_progress_timeout),
+            ("cost_model_timeout", self.cost_model_timeout),
+        ];
+        for (name, timeout) in timeouts {
+            anyhow::ensure!(!timeout.is_zero(), "network.{name} must be greater than 0");
+            anyhow::ensure!(
+                timeout < self.update_interval,
+                "network.{name} must be less than network.update_interval",
+            );
+        }
+        anyhow::ensure!(
+            !self.health_probe_timeout.is_zero()
+                && self.health_probe_timeout < self.health_probe_interval,
+            "network.health_probe_timeout must be between 0 and network.health_probe_interval",
+        );
+        anyhow::ensure!(
+            self.subgraph_page_size > 0,
+            "network.subgraph_page_size must be greater than 0",
+        );
+        anyhow::ensure!(
+            !self.subgraph_full_sync_interval.is_zero(),
+            "network.subgraph_full_sync_interval must be greater than 0",
+        );
+        anyhow::ensure!(
+            self.health_probe_failure_threshold > 0,
+            "network.health_probe_failure_threshold must be greater than 0",
+        );
+        anyhow::ensure!(
+            self.host_resolution_min_ttl <= self.host_resolution_max_ttl,
+            "network.host_resolution_min_ttl must not be greater than network.host_resolution_max_ttl",
+        );
+        if let Some(factor) = self.adaptive_timeout_factor {
+            // Timeouts are recorded as latency, so a factor of 1 would never let them grow
+            anyhow::ensure!(
+                factor > 1.0 && factor <= MAX_ADAPTIVE_TIMEOUT_FACTOR,
+                "network.adaptive_timeout_factor must be greater than 1 and at most {MAX_ADAPTIVE_TIMEOUT_FACTOR}",
+            );
+            anyhow::ensure!(
+                !self.adaptive_timeout_min.is_zero()
+                    && self.adaptive_timeout_min <= self.indexing_progress_timeout,
+                "network.adaptive_timeout_min must be between 0 and network.indexing_progress_timeout",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// API key spend tracking configuration. All durations are in seconds.
+///
+/// See [`Config`]'s [`spend_tracking`](struct.Config.html#structfield.spend_tracking).
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SpendTrackingConfig {
+    /// Sliding period over which the spend is accumulated. Defaults to 30 days. Must be at least
+    /// 1 hour, the duration of the spend buckets.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub period: Duration,
+    /// File path where the spend counters are persisted. Not persisted if not set.
+    pub path: Option<PathBuf>,
+    /// Interval between writes of the spend counters to the file. Defaults to 60. Must be greater
+    /// than 0.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub persist_interval: Duration,
+}
+
+impl Default for SpendTrackingConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(30 * 24 * 60 * 60),
+            path: None,
+            persist_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SpendTrackingConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.period.as_secs() >= SPEND_BUCKET_SECS,
+            "spend_tracking.period must be at least {SPEND_BUCKET_SECS}",
+        );
+        anyhow::ensure!(
+            !self.persist_interval.is_zero(),
+            "spend_tracking.persist_interval must be greater than 0",
+        );
+        Ok(())
+    }
+}
+
+/// Persisted network topology configuration.
+///
+/// See [`Config`]'s [`topology_snapshot`](struct.Config.html#structfield.topology_snapshot).
+#[serde_as]
+#[derive(Deserialize)]
+pub struct TopologySnapshotConfig {
+    /// File path where the latest network subgraph data is persisted
+    pub path: PathBuf,
+    /// Maximum age, in seconds, of the persisted data for it to be used on startup
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_staleness: Duration,
+}
+
+/// Automatic POI divergence detection configuration.
+///
+/// See [`Config`]'s [`poi_divergence`](struct.Config.html#structfield.poi_divergence).
+#[serde_as]
+#[derive(Deserialize)]
+pub struct PoiDivergenceConfig {
+    /// Number of deployments to check, among the ones with the most indexers
+    pub deployments: usize,
+    /// Interval between checks, in seconds
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// Block the indexers reporting a minority POI from serving queries for the deployment
+    #[serde(default)]
+    pub auto_quarantine: bool,
+}
+
+impl PoiDivergenceConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.interval.is_zero(),
+            "poi_divergence.interval must be greater than 0",
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BlockedPoi {
     pub public_poi: B256,
@@ -187,17 +593,116 @@ pub struct BlockedPoi {
 /// Load the configuration from a JSON file.
 pub fn load_from_file(path: &Path) -> anyhow::Result<Config> {
     let config_content = std::fs::read_to_string(path)?;
-    let config = serde_json::from_str(&config_content)?;
+    let config: Config = serde_json::from_str(&config_content)?;
+    anyhow::ensure!(
+        config.static_topology.is_some() || !config.trusted_indexers.is_empty(),
+        "trusted_indexers must not be empty without a static_topology",
+    );
+    if let Some(quorum) = config.trusted_indexers_quorum {
+        anyhow::ensure!(
+            (1..=config.trusted_indexers.len()).contains(&quorum),
+            "trusted_indexers_quorum must be between 1 and the number of trusted indexers",
+        );
+    }
+    config.network.validate()?;
+    config.spend_tracking.validate()?;
+    if let Some(poi_divergence) = &config.poi_divergence {
+        poi_divergence.validate()?;
+    }
     Ok(config)
 }
 
-/// Load the IP blocklist from a CSV file.
+/// Load the IP blocker DB from a CSV file.
 ///
-/// The CSV file should contain rows of `IpNetwork,Country`.
-pub fn load_ip_blocklist_from_file(path: &Path) -> anyhow::Result<HashSet<IpNetwork>> {
-    let db = std::fs::read_to_string(path).context("IP blocklist DB")?;
-    Ok(db
-        .lines()
-        .filter_map(|line| line.split_once(',')?.0.parse().ok())
-        .collect())
+/// The CSV file should contain rows of `IpNetwork,Country`, with an optional trailing `,ASN`
+/// column. Invalid rows are skipped.
+pub fn load_ip_blocker_db_from_file(path: &Path) -> anyhow::Result<Vec<IpBlockerDbEntry>> {
+    let db = std::fs::read_to_string(path).context("IP blocker DB")?;
+    Ok(db.lines().filter_map(parse_ip_blocker_db_row).collect())
+}
+
+fn parse_ip_blocker_db_row(line: &str) -> Option<IpBlockerDbEntry> {
+    let mut fields = line.split(',').map(str::trim);
+    let network = fields.next()?.parse().ok()?;
+    let country = fields.next()?.to_string();
+    let asn = fields
+        .next()
+        .and_then(|asn| asn.trim_start_matches("AS").parse().ok());
+    Some(IpBlockerDbEntry {
+        network,
+        country,
+        asn,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_ip_blocker_db_row, NetworkConfig, PoiDivergenceConfig, SpendTrackingConfig};
+
+    #[test]
+    fn validate_network_config() {
+        assert!(NetworkConfig::default().validate().is_ok());
+
+        let conf: NetworkConfig =
+            serde_json::from_str(r#"{ "update_interval": 10, "poi_timeout": 3 }"#).unwrap();
+        assert_eq!(conf.update_interval, Duration::from_secs(10));
+        assert_eq!(conf.poi_timeout, Duration::from_secs(3));
+        // The default indexing progress timeout exceeds the update interval
+        assert!(conf.validate().is_err());
+
+        let conf: NetworkConfig = serde_json::from_str(r#"{ "version_timeout": 0 }"#).unwrap();
+        assert!(conf.validate().is_err());
+
+        let conf: NetworkConfig = serde_json::from_str(r#"{ "subgraph_page_size": 0 }"#).unwrap();
+        assert!(conf.validate().is_err());
+
+        for factor in ["0.5", "1.0", "1000.0"] {
+            let conf: NetworkConfig =
+                serde_json::from_str(&format!(r#"{{ "adaptive_timeout_factor": {factor} }}"#))
+                    .unwrap();
+            assert!(conf.validate().is_err(), "{factor}");
+        }
+        let conf: NetworkConfig =
+            serde_json::from_str(r#"{ "adaptive_timeout_factor": 1.5 }"#).unwrap();
+        assert!(conf.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_poi_divergence_config() {
+        let conf: PoiDivergenceConfig =
+            serde_json::from_str(r#"{ "deployments": 10, "interval": 600 }"#).unwrap();
+        assert!(conf.validate().is_ok());
+
+        let conf: PoiDivergenceConfig =
+            serde_json::from_str(r#"{ "deployments": 10, "interval": 0 }"#).unwrap();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn validate_spend_tracking_config() {
+        assert!(SpendTrackingConfig::default().validate().is_ok());
+
+        let conf: SpendTrackingConfig =
+            serde_json::from_str(r#"{ "persist_interval": 0 }"#).unwrap();
+        assert!(conf.validate().is_err());
+
+        let conf: SpendTrackingConfig = serde_json::from_str(r#"{ "period": 60 }"#).unwrap();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn parse_ip_blocker_db_rows() {
+        let entry = parse_ip_blocker_db_row("10.0.0.0/8,US").expect("valid row");
+        assert_eq!(entry.network.to_string(), "10.0.0.0/8");
+        assert_eq!(entry.country, "US");
+        assert_eq!(entry.asn, None);
+
+        let entry = parse_ip_blocker_db_row("10.0.0.0/8,US,AS1234").expect("valid row");
+        assert_eq!(entry.asn, Some(1234));
+
+        assert!(parse_ip_blocker_db_row("10.0.0.0/8").is_none());
+        assert!(parse_ip_blocker_db_row("invalid,US").is_none());
+    }
 }
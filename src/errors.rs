@@ -73,6 +73,9 @@ pub enum IndexerError {
     /// The indexer’s response is bad.
     #[error("BadResponse({0:#})")]
     BadResponse(String),
+    /// The indexer rejected the receipt, since its allocation is unknown or closed.
+    #[error("AllocationClosed({0:#})")]
+    AllocationClosed(String),
 }
 
 #[derive(thiserror::Error, Clone, Debug)]
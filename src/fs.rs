@@ -0,0 +1,56 @@
+//! File system helpers for the gateway state persisted to local files.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+
+/// Write the contents to the file, without blocking the async runtime.
+///
+/// The contents are written to a temporary file first, and then moved to the final path. This
+/// avoids leaving a partially written file behind.
+pub async fn write_atomic(path: PathBuf, contents: Vec<u8>) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic_blocking(&path, &contents))
+        .await
+        .context("file write task failed")?
+}
+
+fn write_atomic_blocking(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Returns a path in the temporary directory, unique to the test process and call.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("{}-{n}-{name}", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{temp_path, write_atomic};
+
+    #[tokio::test]
+    async fn write_file_atomically() {
+        //* Given
+        let path = temp_path("write-atomic.json");
+        std::fs::write(&path, "previous").unwrap();
+
+        //* When
+        let result = write_atomic(path.clone(), b"{}".to_vec()).await;
+        let content = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+
+        //* Then
+        assert!(result.is_ok());
+        assert_eq!(content.unwrap(), "{}");
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
@@ -59,8 +59,21 @@ impl IndexerClient {
             .body(query.to_string())
             .send()
             .await;
-        let response = match result.and_then(|r| r.error_for_status()) {
-            Ok(response) => response,
+        let response = match result {
+            Ok(response) if response.status().is_client_error() => {
+                let status_err = response.error_for_status_ref().err();
+                let body = response.text().await.unwrap_or_default();
+                if is_allocation_closed_error(&body) {
+                    return Err(AllocationClosed(body.chars().take(256).collect()));
+                }
+                return Err(BadResponse(
+                    status_err.map(|err| err.to_string()).unwrap_or_default(),
+                ));
+            }
+            Ok(response) => match response.error_for_status() {
+                Ok(response) => response,
+                Err(err) => return Err(BadResponse(err.to_string())),
+            },
             Err(err) if err.is_timeout() => return Err(Timeout),
             Err(err) => {
                 return match err.status() {
@@ -83,6 +96,9 @@ impl IndexerClient {
             .await
             .map_err(|err| BadResponse(err.to_string()))?;
         if let Some(err) = payload.error {
+            if is_allocation_closed_error(&err) {
+                return Err(AllocationClosed(err));
+            }
             return Err(BadResponse(err));
         }
 
@@ -208,6 +224,20 @@ fn rewrite_response(
     Ok((client_response, payload.errors, block))
 }
 
+/// Returns true if the indexer-service error message reports that the receipt's allocation is
+/// unknown to the indexer, or closed.
+fn is_allocation_closed_error(err: &str) -> bool {
+    const PATTERNS: [&str; 5] = [
+        "allocation not found",
+        "allocation id not found",
+        "unknown allocation",
+        "allocation closed",
+        "not eligible for this indexer",
+    ];
+    let err = err.to_ascii_lowercase();
+    PATTERNS.iter().any(|pattern| err.contains(pattern))
+}
+
 fn check_block_error(err: &str) -> Result<(), MissingBlockError> {
     // TODO: indexers should *always* report their block status in a header on every query. This
     // will significantly reduce how brittle this feedback is, and also give a stronger basis for
@@ -247,4 +277,21 @@ mod tests {
             assert_eq!(super::check_block_error(input), expected);
         }
     }
+
+    #[test]
+    fn is_allocation_closed_error() {
+        let tests = [
+            ("", false),
+            ("Receipt allocation ID `0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2` is not eligible for this indexer", true),
+            ("Allocation not found: 0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2", true),
+            ("No valid receipt or free query auth token provided", false),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(
+                super::is_allocation_closed_error(input),
+                expected,
+                "{input}"
+            );
+        }
+    }
 }
@@ -0,0 +1,224 @@
+//! Active health probing of the indexers' indexer-service endpoints.
+//!
+//! The indexers are probed periodically, independently of client traffic, by requesting their
+//! version and status URLs. Indexers failing a number of consecutive probes are considered
+//! unreachable, and are filtered out of the candidates until a probe succeeds again. Failed probes
+//! are also reported as failed responses to the indexing performance tracker, with the probe
+//! timeout as their latency.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+use thegraph_core::{DeploymentId, IndexerId};
+use tokio::time::MissedTickBehavior;
+use url::Url;
+
+use crate::{
+    indexers, indexing_performance::IndexingPerformance, metrics::METRICS, network::NetworkService,
+};
+
+/// The health state of an indexer, as reported by the probes.
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// The number of consecutive failed probes.
+    pub consecutive_failures: u32,
+}
+
+#[derive(Clone)]
+pub struct IndexerHealth {
+    data: Arc<RwLock<HashMap<IndexerId, Health>>>,
+    failure_threshold: u32,
+}
+
+impl IndexerHealth {
+    /// Spawn the health prober.
+    ///
+    /// Indexers failing `failure_threshold` consecutive probes are considered unreachable.
+    pub fn spawn(
+        client: reqwest::Client,
+        network: NetworkService,
+        indexing_perf: IndexingPerformance,
+        interval: Duration,
+        timeout: Duration,
+        failure_threshold: u32,
+    ) -> Self {
+        let health = Self {
+            data: Default::default(),
+            failure_threshold,
+        };
+        let prober = health.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                timer.tick().await;
+
+                // Collect the indexers and the deployments they serve
+                let mut indexers: HashMap<IndexerId, (Url, Vec<DeploymentId>)> = HashMap::new();
+                for (deployment, indexings) in network.deployment_indexings() {
+                    for (indexer, url, _) in indexings {
+                        indexers
+                            .entry(indexer)
+                            .or_insert_with(|| (url, vec![]))
+                            .1
+                            .push(deployment);
+                    }
+                }
+
+                let probes = indexers.iter().map(|(indexer, (url, _))| {
+                    let client = &client;
+                    async move { (*indexer, probe(client, url, timeout).await) }
+                });
+                let results = futures::future::join_all(probes).await;
+
+                let failed = prober.record(results);
+                let timeout_ms = timeout.as_millis().try_into().unwrap_or(u16::MAX);
+                for indexer in failed {
+                    let deployments = indexers.get(&indexer).map(|(_, d)| d.as_slice());
+                    for deployment in deployments.unwrap_or_default() {
+                        indexing_perf.feedback(indexer, *deployment, false, timeout_ms, None);
+                    }
+                }
+            }
+        });
+        health
+    }
+
+    /// Record the results of a round of probes, dropping the indexers that were not probed.
+    ///
+    /// Returns the indexers that failed their probe.
+    fn record(&self, results: Vec<(IndexerId, anyhow::Result<u16>)>) -> Vec<IndexerId> {
+        let probed: HashSet<IndexerId> = results.iter().map(|(indexer, _)| *indexer).collect();
+        let mut data = self.data.write();
+        data.retain(|indexer, _| probed.contains(indexer));
+        METRICS.indexer_probe_latency_ms.reset();
+        METRICS.indexer_probe_failures.reset();
+        let mut failed = vec![];
+        for (indexer, result) in results {
+            let health = data.entry(indexer).or_default();
+            let label = format!("{indexer:?}");
+            match result {
+                Ok(latency_ms) => {
+                    health.consecutive_failures = 0;
+                    METRICS
+                        .indexer_probe_latency_ms
+                        .with_label_values(&[&label])
+                        .set(latency_ms as i64);
+                }
+                Err(probe_err) => {
+                    tracing::debug!(?indexer, %probe_err, "indexer health probe failed");
+                    health.consecutive_failures += 1;
+                    failed.push(indexer);
+                }
+            };
+            METRICS
+                .indexer_probe_failures
+                .with_label_values(&[&label])
+                .set(health.consecutive_failures as i64);
+        }
+        failed
+    }
+
+    /// Returns true if the indexer failed the last `failure_threshold` consecutive probes.
+    pub fn is_unreachable(&self, indexer: &IndexerId) -> bool {
+        self.data
+            .read()
+            .get(indexer)
+            .map(|health| health.consecutive_failures >= self.failure_threshold)
+            .unwrap_or(false)
+    }
+}
+
+/// Probe the indexer's version and status URLs.
+///
+/// Returns the total latency of the requests, in milliseconds.
+async fn probe(client: &reqwest::Client, url: &Url, timeout: Duration) -> anyhow::Result<u16> {
+    let start = Instant::now();
+    let version_url = indexers::version_url(url);
+    client
+        .get(version_url.as_str())
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let status_url = indexers::status_url(url);
+    client
+        .post(status_url.as_str())
+        .timeout(timeout)
+        .json(&serde_json::json!({ "query": "{ version { version } }" }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(start.elapsed().as_millis().try_into().unwrap_or(u16::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use anyhow::anyhow;
+    use parking_lot::RwLock;
+    use thegraph_core::{alloy::primitives::Address, IndexerId};
+
+    use super::{Health, IndexerHealth};
+
+    #[test]
+    fn probe_state_transitions() {
+        //* Given
+        let indexer = IndexerId::from(Address::repeat_byte(1));
+        let other = IndexerId::from(Address::repeat_byte(2));
+        let health = IndexerHealth {
+            data: Arc::new(RwLock::new(HashMap::new())),
+            failure_threshold: 3,
+        };
+
+        //* When
+        let mut unreachable = vec![];
+        let mut failed = vec![];
+        for probe_ok in [true, false, false, false, false, true] {
+            let result = match probe_ok {
+                true => Ok(10),
+                false => Err(anyhow!("timeout")),
+            };
+            failed.push(health.record(vec![(indexer, result), (other, Ok(10))]));
+            unreachable.push(health.is_unreachable(&indexer));
+        }
+        health.record(vec![(other, Ok(10))]);
+
+        //* Then
+        assert_eq!(unreachable, [false, false, false, true, true, false]);
+        assert_eq!(failed.iter().filter(|f| f.contains(&indexer)).count(), 4);
+        assert!(failed.iter().all(|f| !f.contains(&other)));
+        assert!(!health.data.read().contains_key(&indexer));
+    }
+
+    #[test]
+    fn unreachable_after_consecutive_failures() {
+        //* Given
+        let indexer = IndexerId::from(Address::repeat_byte(1));
+        let health = IndexerHealth {
+            data: Arc::new(RwLock::new(HashMap::new())),
+            failure_threshold: 3,
+        };
+
+        //* When
+        let mut results = vec![];
+        for consecutive_failures in [0, 2, 3] {
+            health.data.write().insert(
+                indexer,
+                Health {
+                    consecutive_failures,
+                },
+            );
+            results.push(health.is_unreachable(&indexer));
+        }
+
+        //* Then
+        assert_eq!(results, [false, false, true]);
+        assert!(!health.is_unreachable(&IndexerId::from(Address::repeat_byte(2))));
+    }
+}
@@ -12,6 +12,7 @@ const COST_MODEL_QUERY_DOCUMENT: &str = r#"
         costModels(deployments: $deployments) {
             deployment
             model
+            variables
         }
     }
 "#;
@@ -105,8 +106,11 @@ struct Response {
     cost_models: Vec<CostModelSource>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct CostModelSource {
     pub deployment: DeploymentId,
     pub model: String,
+    /// Cost model global variables, as a JSON object
+    #[serde(default)]
+    pub variables: Option<serde_json::Value>,
 }
@@ -9,15 +9,19 @@ mod client_query;
 mod config;
 mod errors;
 mod exchange_rate;
+mod fs;
 mod graphql;
 mod http_ext;
 mod indexer_client;
+mod indexer_health;
 mod indexers;
 mod indexing_performance;
 mod json;
 mod metrics;
 mod middleware;
 mod network;
+mod query_features;
+mod query_policy;
 mod receipts;
 mod reports;
 mod subgraph_studio;
@@ -28,7 +32,7 @@ mod unattestable_errors;
 mod vouchers;
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
     io::Write as _,
     net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -37,10 +41,14 @@ use std::{
     time::Duration,
 };
 
-use auth::AuthContext;
+use auth::{
+    admin::{handle_api_key_updates, ApiKeyAdmin},
+    APIKey, AuthContext, JwtIssuers, RateLimiter, SpendTracker,
+};
 use axum::{
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, State},
     http::{self, status::StatusCode},
+    response::sse::{self, Sse},
     routing, Router,
 };
 use budgets::{Budgeter, USD};
@@ -48,18 +56,31 @@ use chains::Chains;
 use client_query::context::Context;
 use config::{ApiKeys, ExchangeRateProvider};
 use indexer_client::IndexerClient;
+use indexer_health::IndexerHealth;
 use indexing_performance::IndexingPerformance;
 use middleware::{
     legacy_auth_adapter, RequestTracingLayer, RequireAuthorizationLayer, SetRequestIdLayer,
 };
-use network::subgraph_client::Client as SubgraphClient;
+use network::{
+    indexer_host_policy::HostPolicy,
+    indexer_indexing_poi_resolver::PoiResolver,
+    persisted_topology::PersistedTopology,
+    poi_divergence::{self, PoiQuarantine},
+    service::TopologySource,
+    subgraph_client::Client as SubgraphClient,
+    topology_events::TopologyEvent,
+};
 use prometheus::{self, Encoder as _};
 use receipts::ReceiptSigner;
 use thegraph_core::{
     alloy::{dyn_abi::Eip712Domain, primitives::ChainId, signers::local::PrivateKeySigner},
     attestation,
 };
-use tokio::{net::TcpListener, signal::unix::SignalKind, sync::watch};
+use tokio::{
+    net::TcpListener,
+    signal::unix::SignalKind,
+    sync::{broadcast, watch},
+};
 use tower_http::cors::{self, CorsLayer};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
@@ -104,29 +125,80 @@ async fn main() {
     let indexer_client = IndexerClient {
         client: http_client.clone(),
     };
-    let network_subgraph_client = SubgraphClient {
-        client: indexer_client.clone(),
-        indexers: conf.trusted_indexers,
-        latest_block: None,
-        page_size: 500,
+    let topology_source = match conf.static_topology {
+        Some(path) => TopologySource::StaticFile(path),
+        None => TopologySource::NetworkSubgraph(SubgraphClient::new(
+            indexer_client.clone(),
+            conf.trusted_indexers,
+            conf.network.subgraph_page_size,
+            conf.network.subgraph_full_sync_interval,
+            conf.trusted_indexers_quorum.unwrap_or(1),
+        )),
     };
-    let indexer_host_blocklist = match &conf.ip_blocker_db {
+    let indexer_host_db = match &conf.ip_blocker_db {
         Some(path) => {
-            config::load_ip_blocklist_from_file(path).expect("failed to load IP blocker DB")
+            config::load_ip_blocker_db_from_file(path).expect("failed to load IP blocker DB")
         }
         None => Default::default(),
     };
+    let indexer_host_policy = HostPolicy::new(indexer_host_db, conf.ip_blocker_rules);
+    let poi_quarantine = PoiQuarantine::default();
+    let kafka_producer = reports::create_kafka_producer(conf.kafka).unwrap();
+    let (topology_events, _) = broadcast::channel::<TopologyEvent>(1024);
+    network::topology_events::forward(
+        topology_events.subscribe(),
+        reports::create_json_reporter(
+            kafka_producer.clone(),
+            conf.graph_env_id.clone(),
+            "gateway_topology_events",
+            "event",
+        ),
+    );
     let mut network = network::service::spawn(
         http_client.clone(),
-        network_subgraph_client,
+        topology_source,
         conf.min_indexer_version,
         conf.min_graph_node_version,
         conf.blocked_indexers,
-        indexer_host_blocklist,
+        indexer_host_policy,
         conf.poi_blocklist.clone(),
+        poi_quarantine.clone(),
+        conf.topology_snapshot
+            .map(|conf| PersistedTopology::new(conf.path, conf.max_staleness)),
+        conf.network.clone(),
+        topology_events.clone(),
     );
     let indexing_perf = IndexingPerformance::new(network.clone());
     network.wait_until_ready().await;
+    let indexer_health = IndexerHealth::spawn(
+        http_client.clone(),
+        network.clone(),
+        indexing_perf.clone(),
+        conf.network.health_probe_interval,
+        conf.network.health_probe_timeout,
+        conf.network.health_probe_failure_threshold,
+    );
+
+    if let Some(poi_divergence_conf) = conf.poi_divergence {
+        let events = reports::create_json_reporter(
+            kafka_producer.clone(),
+            conf.graph_env_id.clone(),
+            "gateway_poi_divergences",
+            "divergence",
+        );
+        let poi_resolver = PoiResolver::new(
+            http_client.clone(),
+            conf.network.poi_timeout,
+            conf.network.poi_cache_ttl,
+        );
+        poi_divergence::spawn(
+            poi_resolver,
+            network.clone(),
+            poi_divergence_conf,
+            poi_quarantine,
+            events,
+        );
+    }
 
     let legacy_signer: &'static secp256k1::SecretKey = Box::leak(Box::new(
         secp256k1::SecretKey::from_slice(
@@ -146,8 +218,22 @@ async fn main() {
     )));
 
     // Initialize the auth service
-    let auth_service =
-        init_auth_service(http_client.clone(), conf.api_keys, conf.payment_required).await;
+    let rate_limiter = RateLimiter::new(conf.rate_limit, conf.user_rate_limit);
+    let spend_tracker = SpendTracker::new(conf.spend_tracking.period);
+    if let Some(path) = conf.spend_tracking.path {
+        spend_tracker.persist(path, conf.spend_tracking.persist_interval);
+    }
+    let (auth_service, api_keys) = init_auth_service(
+        http_client.clone(),
+        conf.api_keys,
+        conf.payment_required,
+        rate_limiter,
+        spend_tracker.clone(),
+        JwtIssuers::new(conf.jwt_issuers).expect("invalid JWT issuers"),
+        conf.jwt_max_lifetime,
+        conf.trusted_proxy_hops,
+    )
+    .await;
 
     let budgeter: &'static Budgeter =
         Box::leak(Box::new(Budgeter::new(USD(conf.query_fees_target))));
@@ -159,9 +245,8 @@ async fn main() {
             queries: "gateway_queries",
             attestations: "gateway_attestations",
         },
-        conf.kafka,
-    )
-    .unwrap();
+        kafka_producer,
+    );
 
     let ctx = Context {
         indexer_client,
@@ -170,16 +255,23 @@ async fn main() {
         chains: Box::leak(Box::new(Chains::new(conf.chain_aliases))),
         grt_per_usd,
         indexing_perf,
+        indexer_health,
         network,
         attestation_domain,
         reporter,
+        spend_tracker,
     };
 
     let poi_blocklist: &'static str = serde_json::to_string(&conf.poi_blocklist).unwrap().leak();
 
     // Host metrics on a separate server with a port that isn't open to public requests.
     tokio::spawn(async move {
-        let router = Router::new().route("/metrics", routing::get(handle_metrics));
+        let router = Router::new()
+            .route("/metrics", routing::get(handle_metrics))
+            .route(
+                "/topology/events",
+                routing::get(handle_topology_events).with_state(topology_events),
+            );
 
         let metrics_listener = TcpListener::bind(SocketAddr::new(
             IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
@@ -265,6 +357,16 @@ async fn main() {
             }),
         )
         .nest("/api", api);
+    let router = match conf.admin_auth_token.filter(|token| !token.is_empty()) {
+        Some(auth_token) => router.route(
+            "/admin/api-keys",
+            routing::post(handle_api_key_updates).with_state(ApiKeyAdmin {
+                auth_token,
+                api_keys,
+            }),
+        ),
+        None => router,
+    };
 
     let app_listener = TcpListener::bind(SocketAddr::new(
         IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
@@ -324,6 +426,24 @@ async fn handle_metrics() -> impl axum::response::IntoResponse {
     (StatusCode::OK, buffer)
 }
 
+/// Stream the network topology change events, as server-sent events.
+async fn handle_topology_events(
+    State(events): State<broadcast::Sender<TopologyEvent>>,
+) -> Sse<impl futures::Stream<Item = Result<sse::Event, axum::Error>>> {
+    let stream = futures::stream::unfold(events.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((sse::Event::default().json_data(event), events)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "topology events dropped");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(sse::KeepAlive::default())
+}
+
 pub fn init_logging(executable_name: &str, json: bool) {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::try_new(format!("info,{executable_name}=debug")).unwrap());
@@ -342,33 +462,50 @@ pub fn init_logging(executable_name: &str, json: bool) {
         .init();
 }
 
-/// Creates a new [`AuthContext`] from the given configuration.
+/// Creates a new [`AuthContext`] from the given configuration, along with the sender of its API
+/// keys map.
 ///
 /// This functions awaits the completion of the initial API keys fetch.
 async fn init_auth_service(
     http: reqwest::Client,
     config: Option<ApiKeys>,
     payment_required: bool,
-) -> AuthContext {
+    rate_limiter: RateLimiter,
+    spend_tracker: SpendTracker,
+    jwt_issuers: JwtIssuers,
+    jwt_max_lifetime: Duration,
+    trusted_proxy_hops: usize,
+) -> (AuthContext, Arc<watch::Sender<HashMap<String, APIKey>>>) {
     let special_api_keys = match &config {
-        Some(ApiKeys::Endpoint { special, .. }) => Arc::new(HashSet::from_iter(special.clone())),
+        Some(ApiKeys::Endpoint { special, .. } | ApiKeys::File { special, .. }) => {
+            Arc::new(HashSet::from_iter(special.clone()))
+        }
         _ => Default::default(),
     };
 
-    let api_keys = match config {
-        Some(ApiKeys::Endpoint { url, auth, .. }) => {
-            subgraph_studio::api_keys(http, url, auth).await
+    let api_keys = Arc::new(watch::channel(Default::default()).0);
+    match config {
+        Some(ApiKeys::Endpoint {
+            url, auth, cache, ..
+        }) => subgraph_studio::api_keys(http, url, auth, cache, api_keys.clone()).await,
+        Some(ApiKeys::File { path, .. }) => {
+            auth::key_file::api_keys(path, api_keys.clone()).expect("failed to load API keys file")
         }
-        Some(ApiKeys::Fixed(api_keys)) => {
-            let api_keys = api_keys.into_iter().map(|k| (k.key.clone(), k)).collect();
-            watch::channel(api_keys).1
+        Some(ApiKeys::Fixed(fixed)) => {
+            api_keys.send_replace(fixed.into_iter().map(|k| (k.key.clone(), k)).collect());
         }
-        None => watch::channel(Default::default()).1,
+        None => (),
     };
 
-    AuthContext {
+    let ctx = AuthContext {
         payment_required,
-        api_keys,
+        api_keys: api_keys.subscribe(),
         special_api_keys,
-    }
+        rate_limiter,
+        spend_tracker,
+        jwt_issuers: Arc::new(jwt_issuers),
+        jwt_max_lifetime,
+        trusted_proxy_hops,
+    };
+    (ctx, api_keys)
 }
@@ -2,8 +2,8 @@ use lazy_static::lazy_static;
 use prometheus::{
     core::{MetricVec, MetricVecBuilder},
     register_gauge, register_histogram, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, register_int_gauge_vec, Gauge, Histogram, HistogramVec, IntCounter,
-    IntCounterVec, IntGaugeVec,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Gauge, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 
 lazy_static! {
@@ -18,6 +18,20 @@ pub struct Metrics {
     pub partial_voucher: ResponseMetrics,
     pub voucher: ResponseMetrics,
     pub blocks_per_minute: IntGaugeVec,
+    pub indexer_blocks: IntGaugeVec,
+    pub indexer_block_remaining_seconds: IntGaugeVec,
+    pub indexer_host_blocks: IntGaugeVec,
+    pub network_subgraph_divergence: IntCounterVec,
+    pub network_subgraph_quorum_err: IntCounter,
+    pub network_topology_age_seconds: IntGauge,
+    pub cost_model_fetch_err: IntCounterVec,
+    pub cost_model_stale_fees: IntCounterVec,
+    pub poi_divergence: IntGaugeVec,
+    pub indexer_probe_latency_ms: IntGaugeVec,
+    pub indexer_probe_failures: IntGaugeVec,
+    pub closed_allocations: IntCounterVec,
+    pub api_keys_sync_age_seconds: IntGauge,
+    pub api_keys_sync_err: IntCounter,
 }
 
 impl Metrics {
@@ -46,6 +60,86 @@ impl Metrics {
                 &["chain"]
             )
             .unwrap(),
+            indexer_blocks: register_int_gauge_vec!(
+                "gw_indexer_blocks",
+                "active indexer blocks",
+                &["indexer", "deployment"]
+            )
+            .unwrap(),
+            indexer_block_remaining_seconds: register_int_gauge_vec!(
+                "gw_indexer_block_remaining_seconds",
+                "remaining time of time-limited indexer blocks, in seconds",
+                &["indexer", "deployment"]
+            )
+            .unwrap(),
+            indexer_host_blocks: register_int_gauge_vec!(
+                "gw_indexer_host_blocks",
+                "indexers blocked by each host policy rule",
+                &["rule"]
+            )
+            .unwrap(),
+            network_subgraph_divergence: register_int_counter_vec!(
+                "gw_network_subgraph_divergence",
+                "network subgraph responses diverging from the primary trusted indexer",
+                &["indexer"]
+            )
+            .unwrap(),
+            network_subgraph_quorum_err: register_int_counter!(
+                "gw_network_subgraph_quorum_err",
+                "network subgraph updates rejected for not reaching quorum"
+            )
+            .unwrap(),
+            network_topology_age_seconds: register_int_gauge!(
+                "gw_network_topology_age_seconds",
+                "age of the network subgraph data in use, in seconds"
+            )
+            .unwrap(),
+            cost_model_fetch_err: register_int_counter_vec!(
+                "gw_cost_model_fetch_err",
+                "indexer cost model fetch errors",
+                &["indexer"]
+            )
+            .unwrap(),
+            cost_model_stale_fees: register_int_counter_vec!(
+                "gw_cost_model_stale_fees",
+                "cached indexer cost models used after a failed fetch",
+                &["indexer"]
+            )
+            .unwrap(),
+            poi_divergence: register_int_gauge_vec!(
+                "gw_poi_divergence",
+                "indexers reporting a minority POI, per deployment",
+                &["deployment"]
+            )
+            .unwrap(),
+            indexer_probe_latency_ms: register_int_gauge_vec!(
+                "gw_indexer_probe_latency_ms",
+                "latency of the last successful indexer health probe, in milliseconds",
+                &["indexer"]
+            )
+            .unwrap(),
+            indexer_probe_failures: register_int_gauge_vec!(
+                "gw_indexer_probe_failures",
+                "consecutive failed indexer health probes",
+                &["indexer"]
+            )
+            .unwrap(),
+            closed_allocations: register_int_counter_vec!(
+                "gw_closed_allocations",
+                "allocations reported closed by indexers",
+                &["indexer"]
+            )
+            .unwrap(),
+            api_keys_sync_age_seconds: register_int_gauge!(
+                "gw_api_keys_sync_age_seconds",
+                "age of the API keys in use, in seconds"
+            )
+            .unwrap(),
+            api_keys_sync_err: register_int_counter!(
+                "gw_api_keys_sync_err",
+                "failed API keys fetches"
+            )
+            .unwrap(),
         }
     }
 }
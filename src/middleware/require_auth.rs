@@ -2,11 +2,17 @@
 
 use std::{
     future::Future,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use axum::http::Request;
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Request, StatusCode},
+    response::IntoResponse as _,
+};
 use headers::{authorization::Bearer, Authorization, HeaderMapExt, Origin};
 use tower::Service;
 
@@ -123,7 +129,10 @@ where
         let origin = req.headers().typed_get::<Origin>().unwrap_or(Origin::NULL);
         tracing::debug!(domain = %origin.hostname());
 
-        let auth = match self.ctx.check(bearer.token(), origin.hostname()) {
+        let client_ip = client_ip(&req, self.ctx.trusted_proxy_hops);
+        tracing::debug!(?client_ip);
+
+        let auth = match self.ctx.check(bearer.token(), origin.hostname(), client_ip) {
             Ok(token) => token,
             Err(err) => {
                 // If the bearer token is invalid, return an error response
@@ -132,6 +141,11 @@ where
         };
         tracing::debug!(user_address = ?auth.user, api_key = %auth.key);
 
+        if let Err(retry_after) = self.ctx.check_rate_limit(&auth) {
+            // If the API key or its user exceeded their rate limit, return an error response
+            return ResponseFuture::error(rate_limited_response(retry_after));
+        }
+
         // Insert the `AuthSettings` extension into the request
         req.extensions_mut().insert(auth);
 
@@ -139,6 +153,44 @@ where
     }
 }
 
+/// Returns the client IP address.
+///
+/// Without trusted proxies, this is the connecting address. Otherwise, each trusted proxy appends
+/// the address it received the request from to the `X-Forwarded-For` header, and the client IP is
+/// the entry appended by the outermost trusted proxy. Entries before it may be forged by the
+/// client, and are ignored.
+fn client_ip<B>(req: &Request<B>, trusted_proxy_hops: usize) -> Option<IpAddr> {
+    if trusted_proxy_hops == 0 {
+        return req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+    }
+    let forwarded_for: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let index = forwarded_for.len().checked_sub(trusted_proxy_hops)?;
+    forwarded_for[index].parse().ok()
+}
+
+/// Create a GraphQL error response, with a `429 Too Many Requests` status and a `Retry-After`
+/// header.
+fn rate_limited_response(retry_after: Duration) -> axum::response::Response {
+    let mut response = graphql::error_response(Error::Auth(anyhow::anyhow!("rate limit exceeded")))
+        .into_response();
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let retry_after_secs = retry_after.as_secs_f64().ceil() as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
 /// A layer that applies [`RequireAuthorization`] which requires the requests to be authorized.
 ///
 /// See [`RequireAuthorization`] for more details.
@@ -178,14 +230,19 @@ mod tests {
     use tokio::sync::watch;
     use tokio_test::assert_ready_ok;
 
-    use super::{AuthContext, AuthSettings, RequireAuthorizationLayer};
-    use crate::auth::APIKey;
+    use super::{client_ip, AuthContext, AuthSettings, RequireAuthorizationLayer};
+    use crate::auth::{APIKey, RateLimit, RateLimiter};
 
     fn test_auth_ctx(key: Option<&str>) -> AuthContext {
         let mut ctx = AuthContext {
             payment_required: false,
             api_keys: watch::channel(Default::default()).1,
             special_api_keys: Default::default(),
+            rate_limiter: Default::default(),
+            spend_tracker: Default::default(),
+            jwt_issuers: Default::default(),
+            jwt_max_lifetime: Default::default(),
+            trusted_proxy_hops: 0,
         };
         if let Some(key) = key {
             ctx.api_keys = watch::channel(HashMap::from([(
@@ -474,4 +531,70 @@ mod tests {
             assert_eq!(auth.key, "0123456789abcdef0123456789abcdef");
         });
     }
+
+    /// If the API key exceeded its rate limit, the middleware should return an error response with
+    /// a `429 Too Many Requests` status and a `Retry-After` header.
+    #[tokio::test]
+    async fn rate_limited_api_key() {
+        //* Given
+        let api_key = "0123456789abcdef0123456789abcdef";
+
+        let mut auth_ctx = test_auth_ctx(Some(api_key));
+        auth_ctx.rate_limiter = RateLimiter::new(
+            Some(RateLimit {
+                queries_per_second: 0.1,
+                burst: 1,
+            }),
+            None,
+        );
+
+        let (mut svc, mut handle) =
+            tower_test::mock::spawn_layer(RequireAuthorizationLayer::new(auth_ctx));
+
+        //* When
+        // The service must be ready before calling it
+        handle.allow(2);
+        assert_ready_ok!(svc.poll_ready());
+        let _ = svc.call(test_req_with_auth_header(api_key));
+        assert_ready_ok!(svc.poll_ready());
+        let res = svc.call(test_req_with_auth_header(api_key)).await;
+
+        //* Then
+        assert_matches!(res, Ok(mut res) => {
+            assert_eq!(res.status(), http::StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                res.headers().get(http::header::RETRY_AFTER),
+                Some(&http::HeaderValue::from(10_u64))
+            );
+            assert_matches!(deserialize_graphql_response_body::<()>(res.body_mut()).await, Ok(res_body) => {
+                assert_eq!(res_body.errors.len(), 1);
+                assert_eq!(res_body.errors[0].message, "auth error: rate limit exceeded");
+            });
+        });
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        //* Given
+        let connect_addr: std::net::SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut req = http::Request::builder()
+            .header("x-forwarded-for", "1.1.1.1, 2.2.2.2")
+            .header("x-forwarded-for", "3.3.3.3")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(axum::extract::ConnectInfo(connect_addr));
+
+        //* When
+        let direct = client_ip(&req, 0);
+        let one_hop = client_ip(&req, 1);
+        let two_hops = client_ip(&req, 2);
+        let too_many_hops = client_ip(&req, 4);
+
+        //* Then
+        assert_eq!(direct, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(one_hop, Some("3.3.3.3".parse().unwrap()));
+        assert_eq!(two_hops, Some("2.2.2.2".parse().unwrap()));
+        assert_eq!(too_many_hops, None);
+    }
 }
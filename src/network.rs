@@ -3,11 +3,14 @@
 //! smart contract, as well as the indexers that are indexing them.
 
 pub use errors::{DeploymentError, ResolutionError, SubgraphError, UnavailableReason};
-pub use internal::{Indexing, IndexingId};
+pub use internal::{Indexing, IndexingAllocation, IndexingId};
 pub use service::{NetworkService, ResolvedSubgraphInfo};
 
+pub mod closed_allocations;
 mod config;
 mod errors;
+pub mod indexer_blocklist;
+pub mod indexer_host_policy;
 pub mod indexer_host_resolver;
 pub mod indexer_indexing_cost_model_resolver;
 pub mod indexer_indexing_poi_blocklist;
@@ -15,5 +18,9 @@ pub mod indexer_indexing_poi_resolver;
 pub mod indexer_indexing_progress_resolver;
 pub mod indexer_version_resolver;
 pub mod internal;
+pub mod persisted_topology;
+pub mod poi_divergence;
 pub mod service;
+pub mod static_topology;
 pub mod subgraph_client;
+pub mod topology_events;
@@ -0,0 +1,225 @@
+//! Allocations reported as closed by the indexers.
+//!
+//! The network topology only learns about closed allocations on its next update. Until then, the
+//! allocations indexers reject receipts for are marked as closed, and not used to issue receipts.
+//! Marking an allocation as closed also requests an out-of-cycle network topology update.
+//!
+//! The network subgraph usually lags behind the allocation closures, so the marked allocations
+//! stay marked for [`CLOSED_ALLOCATION_TTL`], even if the next network topology updates still
+//! show them as active. However, indexers also reject receipts for allocations they are not yet
+//! aware of, e.g. newly opened allocations. So the allocations still active in a network topology
+//! fetched at least [`UNMARK_GRACE_PERIOD`] after they were marked as closed are unmarked.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+use thegraph_core::{AllocationId, IndexerId};
+use tokio::sync::Notify;
+
+use super::{
+    errors::{ResolutionError, UnavailableReason},
+    Indexing,
+};
+use crate::{metrics::METRICS, ttl_hash_map::TtlHashMap};
+
+/// The time allocations stay marked as closed, unless unmarked earlier. This must be long enough
+/// for the network subgraph to reflect the allocation closure.
+const CLOSED_ALLOCATION_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The minimum time between marking an allocation as closed and unmarking it, if the network
+/// topology still shows it as active. This covers the usual network subgraph lag, so that closed
+/// allocations are not unmarked by the out-of-cycle updates their marking triggers.
+const UNMARK_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone)]
+pub struct ClosedAllocations {
+    /// The closed allocations, along with their indexer and the time they were marked as closed.
+    allocations: Arc<RwLock<TtlHashMap<AllocationId, (IndexerId, Instant)>>>,
+    refresh: Arc<Notify>,
+}
+
+impl Default for ClosedAllocations {
+    fn default() -> Self {
+        Self {
+            allocations: Arc::new(RwLock::new(TtlHashMap::with_ttl(CLOSED_ALLOCATION_TTL))),
+            refresh: Default::default(),
+        }
+    }
+}
+
+impl ClosedAllocations {
+    /// Mark the indexer's allocation as closed, and request a network topology update.
+    pub fn insert(&self, indexer: IndexerId, allocation: AllocationId) {
+        let mut allocations = self.allocations.write();
+        allocations.cleanup();
+        if allocations
+            .insert(allocation, (indexer, Instant::now()))
+            .is_none()
+        {
+            tracing::info!(?indexer, %allocation, "allocation reported closed");
+            METRICS
+                .closed_allocations
+                .with_label_values(&[&format!("{indexer:?}")])
+                .inc();
+            self.refresh.notify_one();
+        }
+    }
+
+    /// Unmark the allocations marked as closed at least [`UNMARK_GRACE_PERIOD`] before
+    /// `fetched_at`, that are still active in the network topology fetched at that time.
+    pub fn unmark_active(&self, fetched_at: Instant, is_active: impl Fn(&AllocationId) -> bool) {
+        self.allocations
+            .write()
+            .retain(|allocation, (indexer, marked_at)| {
+                let active = (fetched_at.saturating_duration_since(*marked_at)
+                    >= UNMARK_GRACE_PERIOD)
+                    && is_active(allocation);
+                if active {
+                    tracing::info!(?indexer, %allocation, "allocation reported closed is active");
+                }
+                !active
+            });
+    }
+
+    /// Wait for a network topology update to be requested.
+    pub async fn refresh_requested(&self) {
+        self.refresh.notified().await;
+    }
+
+    /// Drop the closed allocations from the indexing. If the largest allocation is closed, the
+    /// largest of the remaining ones takes its place.
+    ///
+    /// Returns an error if all the indexing's allocations are closed.
+    pub fn filter(
+        &self,
+        indexing: Result<Indexing, ResolutionError>,
+    ) -> Result<Indexing, ResolutionError> {
+        let mut indexing = indexing?;
+        let closed = self.allocations.read();
+        if closed.is_empty() {
+            return Ok(indexing);
+        }
+        indexing
+            .allocations
+            .retain(|allocation| closed.get(&allocation.id).is_none());
+        if closed.get(&indexing.largest_allocation).is_none() {
+            return Ok(indexing);
+        }
+        match indexing
+            .allocations
+            .iter()
+            .max_by_key(|a| a.allocated_tokens)
+        {
+            Some(allocation) => {
+                indexing.largest_allocation = allocation.id;
+                Ok(indexing)
+            }
+            None => Err(ResolutionError::Unavailable(UnavailableReason::Blocked(
+                "allocation closed".to_string(),
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use thegraph_core::{alloy::primitives::Address, AllocationId, DeploymentId, IndexerId};
+
+    use super::{ClosedAllocations, UNMARK_GRACE_PERIOD};
+    use crate::network::{
+        internal::{Indexer, IndexingProgress},
+        Indexing, IndexingAllocation, IndexingId,
+    };
+
+    fn allocation(n: u8, allocated_tokens: u128) -> IndexingAllocation {
+        IndexingAllocation {
+            id: AllocationId::from(Address::repeat_byte(n)),
+            allocated_tokens,
+        }
+    }
+
+    fn indexing(allocations: Vec<IndexingAllocation>) -> Indexing {
+        let indexer = IndexerId::from(Address::repeat_byte(1));
+        Indexing {
+            id: IndexingId {
+                indexer,
+                deployment: DeploymentId::new([1; 32].into()),
+            },
+            largest_allocation: allocations[0].id,
+            allocations,
+            total_allocated_tokens: 0,
+            indexer: Arc::new(Indexer {
+                id: indexer,
+                url: "http://indexer.example.com".parse().unwrap(),
+                indexer_service_version: "1.0.0".parse().unwrap(),
+                graph_node_version: "0.35.0".parse().unwrap(),
+                tap_support: true,
+                staked_tokens: 0,
+            }),
+            progress: IndexingProgress {
+                latest_block: 1,
+                min_block: None,
+            },
+            cost_model: Default::default(),
+        }
+    }
+
+    #[test]
+    fn drop_closed_allocations() {
+        //* Given
+        let indexer = IndexerId::from(Address::repeat_byte(1));
+        let closed_allocations = ClosedAllocations::default();
+        closed_allocations.insert(indexer, allocation(1, 0).id);
+
+        //* When
+        let open = closed_allocations.filter(Ok(indexing(vec![
+            allocation(1, 300),
+            allocation(2, 100),
+            allocation(3, 200),
+        ])));
+        let closed = closed_allocations.filter(Ok(indexing(vec![allocation(1, 300)])));
+
+        //* Then
+        let open = open.expect("indexing with open allocations");
+        assert_eq!(open.largest_allocation, allocation(3, 0).id);
+        assert_eq!(
+            open.allocations,
+            vec![allocation(2, 100), allocation(3, 200)]
+        );
+        assert!(closed.is_err());
+    }
+
+    #[test]
+    fn unmark_active_allocations() {
+        //* Given
+        let indexer = IndexerId::from(Address::repeat_byte(1));
+        let closed_allocations = ClosedAllocations::default();
+        let before = Instant::now();
+        closed_allocations.insert(indexer, allocation(1, 0).id);
+        closed_allocations.insert(indexer, allocation(2, 0).id);
+        let after = Instant::now();
+        let active = [allocation(1, 0).id, allocation(3, 0).id];
+
+        //* When
+        // The network topology fetched before the allocations were marked can't confirm them
+        closed_allocations.unmark_active(before, |id| active.contains(id));
+        // The network topology fetched within the grace period may lag behind the closure
+        closed_allocations.unmark_active(after + Duration::from_secs(10), |id| active.contains(id));
+        let marked_before = closed_allocations.allocations.read().len();
+        closed_allocations.unmark_active(after + UNMARK_GRACE_PERIOD, |id| active.contains(id));
+
+        //* Then
+        assert_eq!(marked_before, 2);
+        let closed = closed_allocations.allocations.read();
+        assert!(closed.get(&allocation(1, 0).id).is_none());
+        assert!(closed.get(&allocation(2, 0).id).is_some());
+    }
+}
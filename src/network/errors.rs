@@ -55,8 +55,8 @@ impl From<IndexingError> for ResolutionError {
         match error {
             IndexingError::Indexer(err) => {
                 let reason = match err {
-                    IndexerInfoResolutionError::BlockedHost => {
-                        UnavailableReason::Blocked("host".to_string())
+                    IndexerInfoResolutionError::BlockedHost(rule) => {
+                        UnavailableReason::Blocked(format!("host, {rule}"))
                     }
                     IndexerInfoResolutionError::HostResolutionFailed(err) => {
                         tracing::debug!(error=?err, "host resolution failed");
@@ -137,8 +137,9 @@ pub enum IndexingError {
 /// Errors when processing the indexer information.
 #[derive(Clone, Debug, thiserror::Error)]
 pub enum IndexerInfoResolutionError {
-    #[error("indexer host blocked")]
-    BlockedHost,
+    /// The indexer host is blocked by the host policy rule.
+    #[error("indexer host blocked ({0})")]
+    BlockedHost(String),
     #[error("indexer host resolution failed: {0}")]
     HostResolutionFailed(#[from] HostResolutionError),
     #[error("indexer service version resolution failed: {0}")]
@@ -0,0 +1,162 @@
+//! This module contains the [`IndexerBlocklist`] struct, which is used to block indexers, either
+//! on all their deployments or on a subset of them.
+//!
+//! Blocks may be time-limited. Expired entries are dropped by calling
+//! [`remove_expired`](IndexerBlocklist::remove_expired), which is done before every network
+//! topology update.
+
+use std::collections::BTreeMap;
+
+use thegraph_core::alloy::primitives::Address;
+
+use crate::{config::BlockedIndexer, metrics::METRICS};
+
+/// A blocklist of indexers, with optional expiry timestamps.
+#[derive(Default)]
+pub struct IndexerBlocklist {
+    blocklist: BTreeMap<Address, BlockedIndexer>,
+}
+
+impl IndexerBlocklist {
+    pub fn new(conf: BTreeMap<Address, BlockedIndexer>) -> Self {
+        Self { blocklist: conf }
+    }
+
+    /// Get the active block for the given indexer, if any.
+    pub fn get(&self, indexer: &Address) -> Option<&BlockedIndexer> {
+        self.blocklist.get(indexer)
+    }
+
+    /// Drop the blocks that expired before the given Unix timestamp (in seconds).
+    ///
+    /// If all the deployments of a per-deployment block have expired, the whole entry is dropped.
+    /// This avoids turning a per-deployment block into a block on all deployments.
+    pub fn remove_expired(&mut self, now: u64) {
+        let expired = |until: Option<u64>| until.map(|until| until <= now).unwrap_or(false);
+        self.blocklist.retain(|indexer, entry| {
+            if expired(entry.until) {
+                tracing::info!(?indexer, reason = entry.reason, "indexer block expired");
+                return false;
+            }
+            if entry.deployments.is_empty() {
+                return true;
+            }
+            entry.deployments.retain(|deployment| {
+                if expired(deployment.until) {
+                    tracing::info!(
+                        ?indexer,
+                        deployment = %deployment.id,
+                        reason = entry.reason,
+                        "indexer deployment block expired"
+                    );
+                    return false;
+                }
+                true
+            });
+            !entry.deployments.is_empty()
+        });
+    }
+
+    /// Report the currently active blocks, and their remaining time, in the metrics.
+    pub fn report_metrics(&self, now: u64) {
+        METRICS.indexer_blocks.reset();
+        METRICS.indexer_block_remaining_seconds.reset();
+        for (indexer, entry) in &self.blocklist {
+            let indexer = format!("{indexer:?}");
+            let mut report = |deployment: &str, until: Option<u64>| {
+                let labels = [indexer.as_str(), deployment];
+                METRICS.indexer_blocks.with_label_values(&labels).set(1);
+                if let Some(until) = until {
+                    METRICS
+                        .indexer_block_remaining_seconds
+                        .with_label_values(&labels)
+                        .set(until.saturating_sub(now) as i64);
+                }
+            };
+            if entry.deployments.is_empty() {
+                report("*", entry.until);
+            }
+            for deployment in &entry.deployments {
+                let until = match (entry.until, deployment.until) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                report(&deployment.id.to_string(), until);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use thegraph_core::{alloy::primitives::Address, DeploymentId};
+
+    use super::IndexerBlocklist;
+    use crate::config::{BlockedDeployment, BlockedIndexer};
+
+    fn deployment(n: u8) -> DeploymentId {
+        DeploymentId::new([n; 32].into())
+    }
+
+    fn blocked(deployments: Vec<BlockedDeployment>, until: Option<u64>) -> BlockedIndexer {
+        BlockedIndexer {
+            deployments,
+            reason: "test".to_string(),
+            until,
+        }
+    }
+
+    #[test]
+    fn remove_expired_indexer_blocks() {
+        //* Given
+        let mut blocklist = IndexerBlocklist::new(BTreeMap::from([
+            (Address::repeat_byte(1), blocked(vec![], None)),
+            (Address::repeat_byte(2), blocked(vec![], Some(10))),
+            (Address::repeat_byte(3), blocked(vec![], Some(20))),
+        ]));
+
+        //* When
+        blocklist.remove_expired(10);
+
+        //* Then
+        assert!(blocklist.get(&Address::repeat_byte(1)).is_some());
+        assert!(blocklist.get(&Address::repeat_byte(2)).is_none());
+        assert!(blocklist.get(&Address::repeat_byte(3)).is_some());
+    }
+
+    #[test]
+    fn remove_expired_deployment_blocks() {
+        //* Given
+        let indexer = Address::repeat_byte(1);
+        let mut blocklist = IndexerBlocklist::new(BTreeMap::from([(
+            indexer,
+            blocked(
+                vec![
+                    BlockedDeployment {
+                        id: deployment(1),
+                        until: Some(10),
+                    },
+                    BlockedDeployment {
+                        id: deployment(2),
+                        until: Some(20),
+                    },
+                ],
+                None,
+            ),
+        )]));
+
+        //* When
+        blocklist.remove_expired(10);
+
+        //* Then
+        let entry = blocklist.get(&indexer).expect("entry not removed");
+        assert_eq!(entry.deployments.len(), 1);
+        assert_eq!(entry.deployments[0].id, deployment(2));
+
+        // All per-deployment blocks expired, the entry must not become a block on all deployments
+        blocklist.remove_expired(20);
+        assert!(blocklist.get(&indexer).is_none());
+    }
+}
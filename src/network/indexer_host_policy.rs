@@ -0,0 +1,161 @@
+//! This module contains the [`HostPolicy`] struct, which decides if an indexer must be blocked
+//! based on the IP networks its host resolves to.
+//!
+//! Each IP network of the IP blocker DB is associated with a country and, optionally, an ASN. The
+//! policy rules are applied, in order, to the DB entries matching the indexer's host addresses.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use super::errors::IndexerInfoResolutionError;
+use crate::{
+    config::{HostPolicyAction, HostPolicyRule, IpBlockerDbEntry},
+    metrics::METRICS,
+};
+
+/// Indexer host policy, built from the IP blocker DB and the policy rules.
+#[derive(Default)]
+pub struct HostPolicy {
+    db: Vec<IpBlockerDbEntry>,
+    rules: Vec<HostPolicyRule>,
+}
+
+impl HostPolicy {
+    /// Create a new host policy.
+    ///
+    /// If no rules are given, all the hosts found in the IP blocker DB are blocked.
+    pub fn new(db: Vec<IpBlockerDbEntry>, mut rules: Vec<HostPolicyRule>) -> Self {
+        if rules.is_empty() {
+            rules.push(HostPolicyRule {
+                action: HostPolicyAction::Deny,
+                countries: vec![],
+                asns: vec![],
+            });
+        }
+        Self { db, rules }
+    }
+
+    /// Check the given host addresses against the policy.
+    ///
+    /// Returns the deny rule matched by any of the addresses, if any.
+    pub fn check(&self, addrs: &[IpAddr]) -> Result<(), &HostPolicyRule> {
+        if self.db.is_empty() {
+            return Ok(());
+        }
+        for addr in addrs {
+            // Use the most specific network containing the address
+            let entry = match self
+                .db
+                .iter()
+                .filter(|entry| entry.network.contains(*addr))
+                .max_by_key(|entry| entry.network.prefix())
+            {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let rule = self.rules.iter().find(|rule| {
+                (rule.countries.is_empty() || rule.countries.contains(&entry.country))
+                    && (rule.asns.is_empty()
+                        || entry
+                            .asn
+                            .map(|asn| rule.asns.contains(&asn))
+                            .unwrap_or(false))
+            });
+            match rule {
+                Some(rule) if rule.action == HostPolicyAction::Deny => return Err(rule),
+                _ => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the number of indexers blocked by each of the policy's deny rules in the metrics.
+    pub fn report_metrics<'a, T: 'a>(
+        &self,
+        indexers: impl IntoIterator<Item = &'a Result<T, IndexerInfoResolutionError>>,
+    ) {
+        let mut counts: HashMap<String, i64> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.action == HostPolicyAction::Deny)
+            .map(|rule| (rule.to_string(), 0))
+            .collect();
+        for indexer in indexers {
+            if let Err(IndexerInfoResolutionError::BlockedHost(rule)) = indexer {
+                *counts.entry(rule.clone()).or_default() += 1;
+            }
+        }
+        METRICS.indexer_host_blocks.reset();
+        for (rule, count) in counts {
+            METRICS
+                .indexer_host_blocks
+                .with_label_values(&[&rule])
+                .set(count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::HostPolicy;
+    use crate::config::{HostPolicyAction, HostPolicyRule, IpBlockerDbEntry};
+
+    fn entry(network: &str, country: &str, asn: Option<u32>) -> IpBlockerDbEntry {
+        IpBlockerDbEntry {
+            network: network.parse().unwrap(),
+            country: country.to_string(),
+            asn,
+        }
+    }
+
+    fn rule(action: HostPolicyAction, countries: &[&str], asns: &[u32]) -> HostPolicyRule {
+        HostPolicyRule {
+            action,
+            countries: countries.iter().map(|c| c.to_string()).collect(),
+            asns: asns.to_vec(),
+        }
+    }
+
+    fn addr(addr: &str) -> Vec<IpAddr> {
+        vec![addr.parse().unwrap()]
+    }
+
+    #[test]
+    fn block_all_db_hosts_without_rules() {
+        //* Given
+        let policy = HostPolicy::new(vec![entry("10.0.0.0/8", "US", None)], vec![]);
+
+        //* Then
+        assert!(policy.check(&addr("10.0.0.1")).is_err());
+        assert!(policy.check(&addr("192.168.0.1")).is_ok());
+    }
+
+    #[test]
+    fn apply_first_matching_rule() {
+        //* Given
+        let policy = HostPolicy::new(
+            vec![
+                entry("10.0.0.0/8", "US", Some(1)),
+                entry("10.1.0.0/16", "CA", Some(2)),
+                entry("10.2.0.0/16", "CA", Some(3)),
+            ],
+            vec![
+                rule(HostPolicyAction::Deny, &[], &[3]),
+                rule(HostPolicyAction::Allow, &["CA"], &[]),
+                rule(HostPolicyAction::Deny, &[], &[]),
+            ],
+        );
+
+        //* Then
+        assert_eq!(
+            policy.check(&addr("10.0.0.1")).unwrap_err().to_string(),
+            "deny all"
+        );
+        assert!(policy.check(&addr("10.1.0.1")).is_ok());
+        assert_eq!(
+            policy.check(&addr("10.2.0.1")).unwrap_err().to_string(),
+            "deny asn=AS3"
+        );
+    }
+}
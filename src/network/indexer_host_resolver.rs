@@ -1,13 +1,25 @@
 //! Resolves the IP address of a URL host.
 //!
 //! This module provides a resolver for URL hosts. The resolver caches the results of host
-//! resolution to avoid repeated DNS lookups.
-use std::{borrow::Borrow, collections::HashMap, net::IpAddr, time::Duration};
+//! resolution to avoid repeated DNS lookups, and re-resolves them in the background once they
+//! expire. Hosts not looked up for [`MAX_IDLE_TIME`], e.g. of indexers that left the network, are
+//! evicted from the cache.
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use futures::future::BoxFuture;
 use hickory_resolver::{error::ResolveError, TokioAsyncResolver as DnsResolver};
-use parking_lot::RwLock;
+use parking_lot::Mutex;
 use url::{Host, Url};
 
+/// Time after which the cached resolutions that were not looked up are evicted.
+const MAX_IDLE_TIME: Duration = Duration::from_secs(60 * 60);
+
 /// Error that can occur during URL host resolution.
 #[derive(Clone, Debug, thiserror::Error)]
 pub enum ResolutionError {
@@ -37,83 +49,319 @@ impl ResolutionError {
     }
 }
 
+/// Looks up the IP addresses of a domain, along with the TTL of the DNS records.
+trait DnsLookup: Send + Sync {
+    fn lookup_ip<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<(Vec<IpAddr>, Duration), ResolutionError>>;
+}
+
+impl DnsLookup for DnsResolver {
+    fn lookup_ip<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<(Vec<IpAddr>, Duration), ResolutionError>> {
+        Box::pin(async move {
+            let lookup = DnsResolver::lookup_ip(self, domain).await?;
+            let ttl = lookup
+                .valid_until()
+                .saturating_duration_since(Instant::now());
+            Ok((lookup.iter().collect(), ttl))
+        })
+    }
+}
+
+/// A cached DNS resolution result.
+#[derive(Clone)]
+struct CacheEntry {
+    result: Result<Vec<IpAddr>, ResolutionError>,
+    /// The time after which the entry must be re-resolved.
+    expires_at: Instant,
+    /// Whether a background re-resolution is in progress.
+    refreshing: bool,
+    /// The time of the last lookup of the entry.
+    last_used: Instant,
+}
+
 /// A resolver for URL hosts.
 ///
-/// This resolver caches the results of host resolution to avoid repeated DNS lookups.
+/// This resolver caches the results of host resolution to avoid repeated DNS lookups. Successful
+/// resolutions are cached according to the DNS records TTL, clamped to the configured minimum and
+/// maximum. Failed resolutions are cached for the negative TTL.
+///
+/// Once a cached entry expires, the stale result is still returned while the host is re-resolved
+/// in the background. Only hosts not yet in the cache are resolved in the foreground.
+#[derive(Clone)]
 pub struct HostResolver {
-    inner: DnsResolver,
-    cache: RwLock<HashMap<String, Result<Vec<IpAddr>, ResolutionError>>>,
+    inner: Arc<dyn DnsLookup>,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
     timeout: Duration,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
 }
 
 impl HostResolver {
-    pub fn new(timeout: Duration) -> anyhow::Result<Self> {
+    pub fn new(
+        timeout: Duration,
+        min_ttl: Duration,
+        max_ttl: Duration,
+        negative_ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        let inner = DnsResolver::tokio_from_system_conf()?;
+        Self::with_lookup(Arc::new(inner), timeout, min_ttl, max_ttl, negative_ttl)
+    }
+
+    fn with_lookup(
+        inner: Arc<dyn DnsLookup>,
+        timeout: Duration,
+        min_ttl: Duration,
+        max_ttl: Duration,
+        negative_ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(min_ttl <= max_ttl, "min TTL greater than max TTL");
         Ok(Self {
-            inner: DnsResolver::tokio_from_system_conf()?,
+            inner,
             cache: Default::default(),
             timeout,
+            min_ttl,
+            max_ttl,
+            negative_ttl,
         })
     }
 
     /// Resolve the IP address of the given domain with a timeout.
-    async fn resolve_domain(&self, domain: &str) -> Result<Vec<IpAddr>, ResolutionError> {
-        tokio::time::timeout(self.timeout, self.inner.lookup_ip(domain))
+    ///
+    /// Returns the cache entry for the resolution result.
+    async fn resolve_domain(&self, domain: &str) -> CacheEntry {
+        let now = Instant::now();
+        let result = tokio::time::timeout(self.timeout, self.inner.lookup_ip(domain))
             .await
-            .map_err(|_| ResolutionError::Timeout)?
-            .map_err(Into::into)
-            .map(FromIterator::from_iter)
+            .map_err(|_| ResolutionError::Timeout)
+            .and_then(|res| res);
+        let (result, ttl) = match result {
+            Ok((addrs, ttl)) => (Ok(addrs), ttl.clamp(self.min_ttl, self.max_ttl)),
+            Err(err) => (Err(err), self.negative_ttl),
+        };
+        CacheEntry {
+            result,
+            expires_at: now + ttl,
+            refreshing: false,
+            last_used: now,
+        }
     }
 
-    /// Gets the cached DNS resolution result for the given host.
+    /// Gets the cached DNS resolution result for the given domain.
     ///
-    /// This method locks the cache in read mode and returns the cached information.
-    fn get_from_cache(&self, host: &str) -> Option<Result<Vec<IpAddr>, ResolutionError>> {
-        let cache_read = self.cache.read();
-        cache_read.get(host).cloned()
+    /// If the cached entry has expired, the stale result is returned and a background
+    /// re-resolution is spawned.
+    fn get_from_cache(&self, domain: &str) -> Option<Result<Vec<IpAddr>, ResolutionError>> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+        let entry = cache.get_mut(domain)?;
+        entry.last_used = now;
+        if !entry.refreshing && (entry.expires_at <= now) {
+            entry.refreshing = true;
+            let resolver = self.clone();
+            let domain = domain.to_owned();
+            tokio::spawn(async move {
+                let entry = resolver.resolve_domain(&domain).await;
+                resolver.update_cache(&domain, entry);
+            });
+        }
+        Some(entry.result.clone())
     }
 
-    /// Updates the cache with the given DNS resolution result.
+    /// Updates the cache with the given DNS resolution result, and evicts the idle entries.
     ///
-    /// This method locks the cache in write mode and updates the cache with the given progress
-    /// information.
-    fn update_cache(&self, host: &str, res: Result<Vec<IpAddr>, ResolutionError>) {
-        let mut cache_write = self.cache.write();
-        cache_write.insert(host.to_owned(), res);
+    /// The time of the last lookup of a re-resolved entry is kept.
+    fn update_cache(&self, domain: &str, mut entry: CacheEntry) {
+        let mut cache = self.cache.lock();
+        if let Some(previous) = cache.get(domain) {
+            entry.last_used = previous.last_used;
+        }
+        evict_idle(&mut cache, Instant::now());
+        cache.insert(domain.to_owned(), entry);
     }
 
     /// Resolve the IP address of the given URL.
     ///
     /// The URL is resolved to an IP address. The result is cached so that subsequent calls with the
-    /// same URL will return the same result.
+    /// same URL will return the same result, until it is re-resolved.
     pub async fn resolve_url<U: Borrow<Url>>(
         &self,
         url: U,
     ) -> Result<Vec<IpAddr>, ResolutionError> {
         let url = url.borrow();
+        let domain = match url.host().ok_or(ResolutionError::invalid_url("no host"))? {
+            Host::Ipv4(ip) => return Ok(vec![IpAddr::V4(ip)]),
+            Host::Ipv6(ip) => return Ok(vec![IpAddr::V6(ip)]),
+            Host::Domain(domain) => domain,
+        };
 
-        // Check if the result is already cached, otherwise resolve the URLs' associated IP
+        // Check if the result is already cached, otherwise resolve the domain's associated IP
         // addresses
-        let host_str = url
-            .host_str()
-            .ok_or(ResolutionError::invalid_url("no host"))?;
-
-        match self.get_from_cache(host_str) {
-            Some(state) => state,
-            None => {
-                // Resolve the URL IP addresses
-                let host = url.host().ok_or(ResolutionError::invalid_url("no host"))?;
-
-                let resolution = match host {
-                    Host::Ipv4(ip) => Ok(vec![IpAddr::V4(ip)]),
-                    Host::Ipv6(ip) => Ok(vec![IpAddr::V6(ip)]),
-                    Host::Domain(domain) => self.resolve_domain(domain).await,
-                };
-
-                // Cache the result
-                self.update_cache(host_str, resolution.clone());
-
-                resolution
-            }
+        if let Some(result) = self.get_from_cache(domain) {
+            return result;
         }
+        let entry = self.resolve_domain(domain).await;
+        let result = entry.result.clone();
+        self.update_cache(domain, entry);
+        result
+    }
+}
+
+/// Removes the entries not looked up for [`MAX_IDLE_TIME`].
+fn evict_idle(cache: &mut HashMap<String, CacheEntry>, now: Instant) {
+    cache.retain(|_, entry| now.saturating_duration_since(entry.last_used) < MAX_IDLE_TIME);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use assert_matches::assert_matches;
+    use futures::future::BoxFuture;
+    use url::Url;
+
+    use super::{evict_idle, DnsLookup, HostResolver, ResolutionError, MAX_IDLE_TIME};
+
+    /// Resolves every domain to `10.0.0.<n>`, where `n` is the number of previous lookups.
+    struct StubLookup {
+        lookups: AtomicUsize,
+        ttl: Duration,
+        fail: bool,
+    }
+
+    impl StubLookup {
+        fn new(ttl: Duration, fail: bool) -> Arc<Self> {
+            Arc::new(Self {
+                lookups: AtomicUsize::new(0),
+                ttl,
+                fail,
+            })
+        }
+    }
+
+    impl DnsLookup for StubLookup {
+        fn lookup_ip<'a>(
+            &'a self,
+            _domain: &'a str,
+        ) -> BoxFuture<'a, Result<(Vec<IpAddr>, Duration), ResolutionError>> {
+            let n = self.lookups.fetch_add(1, Ordering::SeqCst);
+            let result = match self.fail {
+                true => Err(ResolutionError::Timeout),
+                false => Ok((vec![IpAddr::from([10, 0, 0, n as u8])], self.ttl)),
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    fn resolver(lookup: Arc<StubLookup>, min_ttl: u64, max_ttl: u64) -> HostResolver {
+        HostResolver::with_lookup(
+            lookup,
+            Duration::from_secs(5),
+            Duration::from_secs(min_ttl),
+            Duration::from_secs(max_ttl),
+            Duration::from_secs(30),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn clamp_ttl() {
+        //* Given
+        let short = resolver(StubLookup::new(Duration::from_secs(1), false), 60, 3600);
+        let long = resolver(StubLookup::new(Duration::from_secs(86400), false), 60, 3600);
+
+        //* When
+        let start = Instant::now();
+        let short_entry = short.resolve_domain("indexer.example.com").await;
+        let long_entry = long.resolve_domain("indexer.example.com").await;
+        let end = Instant::now();
+
+        //* Then
+        assert!(short_entry.expires_at >= start + Duration::from_secs(60));
+        assert!(short_entry.expires_at <= end + Duration::from_secs(60));
+        assert!(long_entry.expires_at >= start + Duration::from_secs(3600));
+        assert!(long_entry.expires_at <= end + Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn cache_failed_resolutions() {
+        //* Given
+        let lookup = StubLookup::new(Duration::from_secs(60), true);
+        let resolver = resolver(lookup.clone(), 60, 3600);
+        let url: Url = "http://indexer.example.com".parse().unwrap();
+
+        //* When
+        let start = Instant::now();
+        let first = resolver.resolve_url(&url).await;
+        let second = resolver.resolve_url(&url).await;
+
+        //* Then
+        assert_matches!(first, Err(ResolutionError::Timeout));
+        assert_matches!(second, Err(ResolutionError::Timeout));
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 1);
+        let expires_at = resolver.cache.lock()["indexer.example.com"].expires_at;
+        assert!(expires_at >= start + Duration::from_secs(30));
+        assert!(expires_at < start + Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn refresh_expired_resolutions_in_background() {
+        //* Given
+        let lookup = StubLookup::new(Duration::ZERO, false);
+        let resolver = resolver(lookup.clone(), 0, 0);
+        let url: Url = "http://indexer.example.com".parse().unwrap();
+
+        //* When
+        let first = resolver.resolve_url(&url).await;
+        let stale = resolver.resolve_url(&url).await;
+        while lookup.lookups.load(Ordering::SeqCst) < 2
+            || resolver.cache.lock()["indexer.example.com"].refreshing
+        {
+            tokio::task::yield_now().await;
+        }
+        let refreshed = resolver.resolve_url(&url).await;
+
+        //* Then
+        assert_eq!(first.unwrap(), vec![IpAddr::from([10, 0, 0, 0])]);
+        assert_eq!(stale.unwrap(), vec![IpAddr::from([10, 0, 0, 0])]);
+        assert_eq!(refreshed.unwrap(), vec![IpAddr::from([10, 0, 0, 1])]);
+    }
+
+    #[tokio::test]
+    async fn evict_idle_resolutions() {
+        //* Given
+        let lookup = StubLookup::new(Duration::from_secs(60), false);
+        let resolver = resolver(lookup.clone(), 60, 3600);
+        let idle: Url = "http://idle.example.com".parse().unwrap();
+        let active: Url = "http://active.example.com".parse().unwrap();
+        let _ = resolver.resolve_url(&idle).await;
+        let _ = resolver.resolve_url(&active).await;
+        let later = Instant::now() + MAX_IDLE_TIME;
+        resolver
+            .cache
+            .lock()
+            .get_mut("active.example.com")
+            .unwrap()
+            .last_used = later;
+
+        //* When
+        evict_idle(&mut resolver.cache.lock(), later);
+
+        //* Then
+        let cache = resolver.cache.lock();
+        assert!(!cache.contains_key("idle.example.com"));
+        assert!(cache.contains_key("active.example.com"));
     }
 }
@@ -1,26 +1,78 @@
 //! Resolves the cost models for the indexers' deployments.
 //!
-//! The cost models are fetched from the indexer's cost URL.
+//! The cost models are fetched from the indexer's cost URL, and compiled into Agora cost models.
+//! The compiled cost models are cached per indexer and deployment, and only recompiled when their
+//! source changes. The cache also serves as a fallback, up to a maximum age, when fetching the
+//! cost models from an indexer fails.
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use cost_model::{Context as AgoraContext, CostModel};
+use custom_debug::CustomDebug;
 use thegraph_core::DeploymentId;
 use url::Url;
 
-use crate::{indexers, indexers::cost_models::CostModelSource};
+use crate::{indexers, indexers::cost_models::CostModelSource, metrics::METRICS};
+
+/// An indexer's cost model for a deployment.
+#[derive(Clone, CustomDebug, Default)]
+pub struct IndexingCostModel {
+    /// The fee of the cost model's `default => x;` statement, if any. This is used when the cost
+    /// model is not available, or fails to evaluate a query.
+    pub default_fee: u128,
+    /// The compiled cost model.
+    #[debug(skip)]
+    pub model: Option<Arc<CostModel>>,
+}
+
+impl IndexingCostModel {
+    /// Evaluate the cost model against the query context.
+    ///
+    /// Falls back to the default fee if the cost model is not available, or if the evaluation
+    /// fails.
+    pub fn fee(&self, context: &mut AgoraContext<'_>) -> u128 {
+        let model = match &self.model {
+            Some(model) => model,
+            None => return self.default_fee,
+        };
+        match model.cost_with_context(context) {
+            Ok(fee) => u128::try_from(&fee).unwrap_or(u128::MAX),
+            Err(cost_model_eval_err) => {
+                tracing::trace!(?cost_model_eval_err);
+                self.default_fee
+            }
+        }
+    }
+}
+
+/// A cached cost model, along with its source and the time it was fetched.
+struct CachedCostModel {
+    source: CostModelSource,
+    cost_model: IndexingCostModel,
+    fetched_at: Instant,
+}
 
 /// Resolve the indexers' cost models sources and compile them into cost models.
+///
+/// The cost models are cached per indexer URL and deployment. If fetching the cost models from an
+/// indexer fails, its cached cost models are used until they are older than the maximum age.
 pub struct CostModelResolver {
     client: reqwest::Client,
     timeout: Duration,
-    cache: parking_lot::Mutex<HashMap<DeploymentId, u128>>,
+    max_age: Duration,
+    cache: parking_lot::Mutex<HashMap<(Url, DeploymentId), CachedCostModel>>,
 }
 
 impl CostModelResolver {
-    pub fn new(client: reqwest::Client, timeout: Duration) -> Self {
+    pub fn new(client: reqwest::Client, timeout: Duration, max_age: Duration) -> Self {
         Self {
             client,
             timeout,
+            max_age,
             cache: Default::default(),
         }
     }
@@ -39,34 +91,112 @@ impl CostModelResolver {
         .map_err(Into::into)
     }
 
-    /// Fetches the cost model sources for the given deployments from the indexer.
+    /// Fetches the cost model sources for the given deployments from the indexer, and compiles
+    /// them into cost models.
     ///
-    /// Returns a map of deployment IDs to the retrieved cost model sources. If certain deployment
-    /// ID's cost model fetch fails, the corresponding value in the map is `None`.
+    /// Returns a map of deployment IDs to the compiled cost models. Deployments without a cost
+    /// model are not present in the map. If the fetch fails, the indexer's cached cost models not
+    /// older than the maximum age are returned.
     pub async fn resolve(
         &self,
         url: &Url,
         indexings: &[DeploymentId],
-    ) -> HashMap<DeploymentId, u128> {
+    ) -> HashMap<DeploymentId, IndexingCostModel> {
         let sources = match self.fetch_cost_model_sources(url, indexings).await {
             Ok(sources) => sources,
             Err(cost_model_err) => {
                 tracing::debug!(%url, %cost_model_err);
-                return self.cache.lock().clone();
+                METRICS
+                    .cost_model_fetch_err
+                    .with_label_values(&[url.as_str()])
+                    .inc();
+                return self.resolve_from_cache(url, indexings, Instant::now());
             }
         };
+        self.update_cache(url, sources, Instant::now())
+    }
 
-        // Only support cost models of the form `default => x;`.
-        let cost_models: HashMap<DeploymentId, u128> = sources
+    /// Replaces the indexer's cached cost models with the fetched sources, only compiling the
+    /// sources that changed. Returns the indexer's cost models.
+    fn update_cache(
+        &self,
+        url: &Url,
+        sources: Vec<CostModelSource>,
+        now: Instant,
+    ) -> HashMap<DeploymentId, IndexingCostModel> {
+        let mut cache = self.cache.lock();
+        cache.retain(|(indexer_url, deployment), _| {
+            indexer_url != url || sources.iter().any(|src| &src.deployment == deployment)
+        });
+        sources
             .into_iter()
-            .filter_map(|src| Some((src.deployment, parse_simple_cost_model(&src.model)?)))
-            .collect();
+            .map(|source| {
+                let deployment = source.deployment;
+                let cost_model = match cache.get_mut(&(url.clone(), deployment)) {
+                    Some(cached) if cached.source == source => {
+                        cached.fetched_at = now;
+                        cached.cost_model.clone()
+                    }
+                    _ => {
+                        let cost_model = compile_cost_model(url, &source);
+                        let cached = CachedCostModel {
+                            source,
+                            cost_model: cost_model.clone(),
+                            fetched_at: now,
+                        };
+                        cache.insert((url.clone(), deployment), cached);
+                        cost_model
+                    }
+                };
+                (deployment, cost_model)
+            })
+            .collect()
+    }
 
-        *self.cache.lock() = cost_models.clone();
+    /// Returns the indexer's cached cost models for the given deployments, dropping the ones older
+    /// than the maximum age.
+    fn resolve_from_cache(
+        &self,
+        url: &Url,
+        indexings: &[DeploymentId],
+        now: Instant,
+    ) -> HashMap<DeploymentId, IndexingCostModel> {
+        let mut cache = self.cache.lock();
+        cache.retain(|_, cached| now.saturating_duration_since(cached.fetched_at) <= self.max_age);
+        let cost_models: HashMap<DeploymentId, IndexingCostModel> = indexings
+            .iter()
+            .filter_map(|deployment| {
+                let cached = cache.get(&(url.clone(), *deployment))?;
+                Some((*deployment, cached.cost_model.clone()))
+            })
+            .collect();
+        METRICS
+            .cost_model_stale_fees
+            .with_label_values(&[url.as_str()])
+            .inc_by(cost_models.len() as u64);
         cost_models
     }
 }
 
+fn compile_cost_model(url: &Url, src: &CostModelSource) -> IndexingCostModel {
+    let globals = match &src.variables {
+        Some(serde_json::Value::String(variables)) => variables.clone(),
+        Some(variables) => variables.to_string(),
+        None => String::new(),
+    };
+    let model = match CostModel::compile(src.model.clone(), &globals) {
+        Ok(model) => Some(Arc::new(model)),
+        Err(cost_model_compile_err) => {
+            tracing::debug!(%url, deployment = %src.deployment, ?cost_model_compile_err);
+            None
+        }
+    };
+    IndexingCostModel {
+        default_fee: parse_simple_cost_model(&src.model).unwrap_or(0),
+        model,
+    }
+}
+
 fn parse_simple_cost_model(src: &str) -> Option<u128> {
     let (_, rest) = src.split_once("default")?;
     let (_, rest) = rest.split_once("=>")?;
@@ -78,6 +208,112 @@ fn parse_simple_cost_model(src: &str) -> Option<u128> {
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, Instant};
+
+    use cost_model::Context as AgoraContext;
+    use thegraph_core::DeploymentId;
+    use url::Url;
+
+    use super::{compile_cost_model, CostModelResolver};
+    use crate::indexers::cost_models::CostModelSource;
+
+    fn source(model: &str) -> CostModelSource {
+        CostModelSource {
+            deployment: DeploymentId::new([1; 32].into()),
+            model: model.to_string(),
+            variables: None,
+        }
+    }
+
+    fn resolver(max_age: Duration) -> CostModelResolver {
+        CostModelResolver::new(reqwest::Client::new(), Duration::from_secs(5), max_age)
+    }
+
+    #[test]
+    fn cost_models_cached_per_indexer() {
+        //* Given
+        let resolver = resolver(Duration::from_secs(60));
+        let deployment = DeploymentId::new([1; 32].into());
+        let indexer_a: Url = "http://a.example.com".parse().unwrap();
+        let indexer_b: Url = "http://b.example.com".parse().unwrap();
+        let now = Instant::now();
+
+        //* When
+        resolver.update_cache(&indexer_a, vec![source("default => 0.1;")], now);
+        resolver.update_cache(&indexer_b, vec![source("default => 0.2;")], now);
+        let cached_a = resolver.resolve_from_cache(&indexer_a, &[deployment], now);
+        let cached_b = resolver.resolve_from_cache(&indexer_b, &[deployment], now);
+        let cached_c = resolver.resolve_from_cache(
+            &"http://c.example.com".parse().unwrap(),
+            &[deployment],
+            now,
+        );
+
+        //* Then
+        assert_eq!(cached_a[&deployment].default_fee, 100_000_000_000_000_000);
+        assert_eq!(cached_b[&deployment].default_fee, 200_000_000_000_000_000);
+        assert!(cached_c.is_empty());
+    }
+
+    #[test]
+    fn discard_expired_cost_models() {
+        //* Given
+        let max_age = Duration::from_secs(60);
+        let resolver = resolver(max_age);
+        let deployment = DeploymentId::new([1; 32].into());
+        let indexer: Url = "http://a.example.com".parse().unwrap();
+        let fetched_at = Instant::now();
+        resolver.update_cache(&indexer, vec![source("default => 0.1;")], fetched_at);
+
+        //* When
+        let fresh = resolver.resolve_from_cache(&indexer, &[deployment], fetched_at + max_age);
+        let expired = resolver.resolve_from_cache(
+            &indexer,
+            &[deployment],
+            fetched_at + max_age + Duration::from_secs(1),
+        );
+        let refetched = resolver.update_cache(
+            &indexer,
+            vec![source("default => 0.3;")],
+            fetched_at + max_age + Duration::from_secs(1),
+        );
+
+        //* Then
+        assert_eq!(fresh.len(), 1);
+        assert!(expired.is_empty());
+        assert_eq!(resolver.cache.lock().len(), 1);
+        assert_eq!(refetched[&deployment].default_fee, 300_000_000_000_000_000);
+    }
+
+    #[test]
+    fn evaluate_cost_model() {
+        //* Given
+        let url: Url = "http://indexer.example.com".parse().unwrap();
+        let cost_model = compile_cost_model(&url, &source("query { a } => 2; default => 0.1;"));
+
+        //* When
+        let fee_a = cost_model.fee(&mut AgoraContext::new("{ a }", "").unwrap());
+        let fee_b = cost_model.fee(&mut AgoraContext::new("{ b }", "").unwrap());
+
+        //* Then
+        assert_eq!(fee_a, 2_000_000_000_000_000_000);
+        assert_eq!(fee_b, 100_000_000_000_000_000);
+    }
+
+    #[test]
+    fn fall_back_to_default_fee() {
+        //* Given
+        let url: Url = "http://indexer.example.com".parse().unwrap();
+        let cost_model = compile_cost_model(&url, &source("default => 0.1; invalid"));
+
+        //* When
+        let fee = cost_model.fee(&mut AgoraContext::new("{ a }", "").unwrap());
+
+        //* Then
+        assert!(cost_model.model.is_none());
+        assert_eq!(fee, 100_000_000_000_000_000);
+    }
+
     #[test]
     fn parse_simple_cost_model() {
         let tests = [
@@ -1,6 +1,9 @@
 //! A resolver that fetches the indexing progress of deployments from an indexer's status URL.
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
 use parking_lot::{Mutex, RwLock};
 use thegraph_core::{alloy::primitives::BlockNumber, DeploymentId};
@@ -37,22 +40,74 @@ pub struct IndexingProgressInfo {
     pub min_block: Option<BlockNumber>,
 }
 
+/// The weight of the latest observation in the indexers' status endpoint latency moving average.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+/// Per-indexer timeouts derived from the observed status endpoint latency.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveTimeout {
+    /// The timeout is this factor times the indexer's average status endpoint latency.
+    pub factor: f64,
+    /// The lower bound of the timeout.
+    pub min: Duration,
+}
+
 /// A resolver that fetches the indexing progress of deployments from an indexer's status URL.
 pub struct IndexingProgressResolver {
     client: reqwest::Client,
     timeout: Duration,
+    adaptive_timeout: Option<AdaptiveTimeout>,
+    /// The moving average of the indexers' status endpoint latency, in milliseconds.
+    latencies: Mutex<HashMap<String, f64>>,
     cache: RwLock<HashMap<String, Mutex<HashMap<DeploymentId, IndexingProgressInfo>>>>,
 }
 
 impl IndexingProgressResolver {
-    pub fn new(client: reqwest::Client, timeout: Duration) -> Self {
+    /// Create a new resolver.
+    ///
+    /// The `timeout` applies to all indexers, unless an `adaptive_timeout` is given. In that case,
+    /// it is the upper bound of the per-indexer timeouts.
+    pub fn new(
+        client: reqwest::Client,
+        timeout: Duration,
+        adaptive_timeout: Option<AdaptiveTimeout>,
+    ) -> Self {
         Self {
             client,
             timeout,
+            adaptive_timeout,
+            latencies: Default::default(),
             cache: Default::default(),
         }
     }
 
+    /// Returns the timeout for requests to the indexer's status URL.
+    fn timeout(&self, url: &str) -> Duration {
+        let latency_ms = self.latencies.lock().get(url).copied();
+        match (self.adaptive_timeout, latency_ms) {
+            (Some(adaptive_timeout), Some(latency_ms)) => {
+                adaptive_timeout_for_latency(adaptive_timeout, self.timeout, latency_ms)
+            }
+            _ => self.timeout,
+        }
+    }
+
+    /// Drop the recorded latencies of the indexers whose URL is not in the given set, e.g. the
+    /// indexers that left the network.
+    pub fn retain_latencies(&self, urls: &HashSet<&str>) {
+        self.latencies
+            .lock()
+            .retain(|url, _| urls.contains(url.as_str()));
+    }
+
+    /// Record the latency of a request to the indexer's status URL.
+    fn record_latency(&self, url: &str, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1e3;
+        let mut latencies = self.latencies.lock();
+        let average = latencies.entry(url.to_string()).or_insert(latency_ms);
+        *average += LATENCY_EWMA_WEIGHT * (latency_ms - *average);
+    }
+
     /// Fetches the indexing progress of the given deployments from the indexer's status URL.
     async fn fetch_indexing_progress(
         &self,
@@ -60,8 +115,9 @@ impl IndexingProgressResolver {
         indexings: &[DeploymentId],
     ) -> HashMap<DeploymentId, Result<Vec<ChainStatus>, ResolutionError>> {
         let status_url = indexers::status_url(url);
+        let start = Instant::now();
         let res = tokio::time::timeout(
-            self.timeout,
+            self.timeout(url.as_str()),
             send_requests(
                 &self.client,
                 status_url,
@@ -70,6 +126,8 @@ impl IndexingProgressResolver {
             ),
         )
         .await;
+        // Timeouts are recorded as well, so that the adaptive timeout grows for slow indexers
+        self.record_latency(url.as_str(), start.elapsed());
 
         match res {
             Ok(res) => res
@@ -125,6 +183,19 @@ impl IndexingProgressResolver {
     }
 }
 
+/// Returns the timeout for an indexer with the given average latency, in milliseconds, bounded by
+/// the adaptive timeout's lower bound and the given maximum.
+fn adaptive_timeout_for_latency(
+    adaptive_timeout: AdaptiveTimeout,
+    max: Duration,
+    latency_ms: f64,
+) -> Duration {
+    // Bound the timeout before the conversion, which fails on overflow
+    let timeout_secs = (latency_ms * adaptive_timeout.factor / 1e3).min(max.as_secs_f64());
+    let timeout = Duration::try_from_secs_f64(timeout_secs.max(0.0)).unwrap_or(max);
+    timeout.clamp(adaptive_timeout.min.min(max), max)
+}
+
 /// Sends requests to the indexer's status URL to fetch the indexing progress of deployments.
 ///
 /// Given a list of deployment IDs, the function groups them into batches of a given size and sends
@@ -175,3 +246,49 @@ async fn send_requests(
     // Merge the responses into a single map
     responses.into_iter().flatten().collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use super::{adaptive_timeout_for_latency, AdaptiveTimeout, IndexingProgressResolver};
+
+    #[test]
+    fn bound_adaptive_timeouts() {
+        //* Given
+        let adaptive_timeout = AdaptiveTimeout {
+            factor: 4.0,
+            min: Duration::from_secs(2),
+        };
+        let max = Duration::from_secs(25);
+
+        //* When
+        let fast = adaptive_timeout_for_latency(adaptive_timeout, max, 100.0);
+        let medium = adaptive_timeout_for_latency(adaptive_timeout, max, 1_500.0);
+        let slow = adaptive_timeout_for_latency(adaptive_timeout, max, 10_000.0);
+        let overflow = adaptive_timeout_for_latency(adaptive_timeout, max, f64::MAX);
+
+        //* Then
+        assert_eq!(fast, Duration::from_secs(2));
+        assert_eq!(medium, Duration::from_secs(6));
+        assert_eq!(slow, Duration::from_secs(25));
+        assert_eq!(overflow, Duration::from_secs(25));
+    }
+
+    #[test]
+    fn retain_latencies_of_current_indexers() {
+        //* Given
+        let resolver =
+            IndexingProgressResolver::new(reqwest::Client::new(), Duration::from_secs(25), None);
+        resolver.record_latency("https://current.example.com/", Duration::from_millis(100));
+        resolver.record_latency("https://gone.example.com/", Duration::from_millis(100));
+
+        //* When
+        resolver.retain_latencies(&HashSet::from(["https://current.example.com/"]));
+
+        //* Then
+        let latencies = resolver.latencies.lock();
+        assert_eq!(latencies.len(), 1);
+        assert!(latencies.contains_key("https://current.example.com/"));
+    }
+}
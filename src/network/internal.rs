@@ -1,14 +1,24 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use thegraph_core::{DeploymentId, IndexerId, SubgraphId};
 
 use self::indexer_processing::IndexerRawInfo;
 pub use self::{
-    snapshot::{Indexing, IndexingId, NetworkTopologySnapshot},
+    snapshot::{
+        Deployment, Indexer, Indexing, IndexingAllocation, IndexingId, IndexingProgress,
+        NetworkTopologySnapshot, Subgraph,
+    },
     state::InternalState,
     subgraph_processing::{AllocationInfo, DeploymentInfo, SubgraphInfo},
 };
-use super::{subgraph_client::Client as SubgraphClient, DeploymentError, SubgraphError};
+use super::{
+    subgraph_client::{types::Subgraph, Client as SubgraphClient},
+    DeploymentError, SubgraphError,
+};
+use crate::time::unix_timestamp;
 
 mod indexer_processing;
 mod pre_processing;
@@ -17,12 +27,29 @@ mod state;
 mod subgraph_processing;
 
 /// Fetch the network topology information from the graph network subgraph.
+///
+/// Expired indexer blocks are dropped from the state before processing the indexers information.
 pub async fn fetch_update(
     network: &PreprocessedNetworkInfo,
-    state: &InternalState,
+    state: &mut InternalState,
 ) -> NetworkTopologySnapshot {
+    let now = unix_timestamp() / 1_000;
+    state.indexer_blocklist.remove_expired(now);
+    state.indexer_blocklist.report_metrics(now);
+    let indexer_urls: HashSet<&str> = network
+        .indexers
+        .values()
+        .map(|indexer| indexer.url.as_str())
+        .collect();
+    state
+        .indexing_progress_resolver
+        .retain_latencies(&indexer_urls);
+
     // Process network topology information
     let indexers_info = indexer_processing::process_info(state, &network.indexers).await;
+    state
+        .indexer_host_policy
+        .report_metrics(indexers_info.values());
     snapshot::new_from(
         indexers_info,
         network.subgraphs.clone(),
@@ -36,23 +63,24 @@ pub struct PreprocessedNetworkInfo {
     indexers: HashMap<IndexerId, IndexerRawInfo>,
 }
 
-/// Fetch the subgraphs information from the graph network subgraph and performs pre-processing
-/// steps, i.e., validation and conversion into the internal representation.
-///
-///   1. Fetch the subgraphs information from the graph network subgraph.
-///   2. Validate and convert the subgraphs fetched info into the internal representation.
+/// Fetch the subgraphs information from the graph network subgraph.
 ///
 /// If the fetch fails or the response is empty, an error is returned.
-///
-/// Invalid info is filtered out before converting into the internal representation.
-pub async fn fetch_and_preprocess_subgraph_info(
+pub async fn fetch_subgraph_info(
     client: &mut SubgraphClient,
     timeout: Duration,
-) -> anyhow::Result<PreprocessedNetworkInfo> {
+) -> anyhow::Result<Vec<Subgraph>> {
     // Fetch the subgraphs information from the graph network subgraph
     let data = tokio::time::timeout(timeout, client.fetch()).await??;
     anyhow::ensure!(!data.is_empty(), "empty subgraph response");
+    Ok(data)
+}
 
+/// Perform the pre-processing steps on the fetched subgraphs information, i.e., validation and
+/// conversion into the internal representation.
+///
+/// Invalid info is filtered out before converting into the internal representation.
+pub fn preprocess_subgraph_info(data: Vec<Subgraph>) -> PreprocessedNetworkInfo {
     // Pre-process (validate and convert) the fetched subgraphs information
     let indexers = pre_processing::into_internal_indexers_raw_info(data.iter());
     let subgraphs = pre_processing::into_internal_subgraphs_raw_info(data.into_iter());
@@ -61,9 +89,66 @@ pub async fn fetch_and_preprocess_subgraph_info(
     let subgraphs = subgraph_processing::process_subgraph_info(subgraphs);
     let deployments = subgraph_processing::process_deployments_info(deployments);
 
-    Ok(PreprocessedNetworkInfo {
+    PreprocessedNetworkInfo {
         subgraphs,
         deployments,
         indexers,
-    })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thegraph_core::{DeploymentId, SubgraphId};
+
+    use super::preprocess_subgraph_info;
+    use crate::{fs::temp_path, network::static_topology};
+
+    #[tokio::test]
+    async fn static_subgraph_resolves_newest_version() {
+        //* Given
+        let subgraph = SubgraphId::new([1; 32].into());
+        let oldest = DeploymentId::new([1; 32].into());
+        let newest = DeploymentId::new([2; 32].into());
+        let deployment = |id: DeploymentId, allocation: u8| {
+            format!(
+                r#"{{
+                    "id": "{id}",
+                    "network": "mainnet",
+                    "allocations": [
+                        {{
+                            "id": "0x00000000000000000000000000000000000000{allocation:02x}",
+                            "indexer": "0x0000000000000000000000000000000000000001"
+                        }}
+                    ]
+                }}"#
+            )
+        };
+        let path = temp_path("static-topology.json");
+        let content = format!(
+            r#"{{
+                "indexers": [
+                    {{ "id": "0x0000000000000000000000000000000000000001", "url": "http://localhost:7600" }}
+                ],
+                "deployments": [{}, {}],
+                "subgraphs": [
+                    {{ "id": "{subgraph}", "versions": ["{oldest}", "{newest}"] }}
+                ]
+            }}"#,
+            deployment(oldest, 2),
+            deployment(newest, 3),
+        );
+        std::fs::write(&path, content).unwrap();
+
+        //* When
+        let data = static_topology::load_from_file(path.clone()).await;
+        let _ = std::fs::remove_file(&path);
+        let network = preprocess_subgraph_info(data.expect("invalid static topology"));
+
+        //* Then
+        let info = network.subgraphs[&subgraph]
+            .as_ref()
+            .expect("invalid subgraph");
+        let versions: Vec<DeploymentId> = info.versions.iter().map(|v| v.deployment_id).collect();
+        assert_eq!(versions, vec![newest, oldest]);
+    }
 }
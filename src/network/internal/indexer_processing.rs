@@ -1,19 +1,20 @@
 use std::collections::{HashMap, HashSet};
 
 use custom_debug::CustomDebug;
-use ipnetwork::IpNetwork;
 use semver::Version;
 use thegraph_core::{alloy::primitives::BlockNumber, AllocationId, DeploymentId, IndexerId};
 use tracing::Instrument;
 use url::Url;
 
-use super::InternalState;
+use super::{IndexingAllocation, InternalState};
 use crate::{
     config::BlockedIndexer,
     network::{
         config::VersionRequirements,
         errors::{IndexerInfoResolutionError, IndexingInfoResolutionError},
+        indexer_host_policy::HostPolicy,
         indexer_host_resolver::HostResolver,
+        indexer_indexing_cost_model_resolver::IndexingCostModel,
         indexer_indexing_poi_blocklist::PoiBlocklist,
         indexer_indexing_poi_resolver::PoiResolver,
         indexer_indexing_progress_resolver::IndexingProgressResolver,
@@ -70,6 +71,8 @@ pub(super) struct IndexerInfo<I> {
 pub(super) struct IndexingRawInfo {
     /// The largest allocation.
     pub largest_allocation: AllocationId,
+    /// All the active allocations.
+    pub allocations: Vec<IndexingAllocation>,
     /// The total amount of tokens allocated.
     pub total_allocated_tokens: u128,
 }
@@ -83,6 +86,9 @@ pub(super) struct IndexingInfo<P, C> {
     /// The largest allocation.
     pub largest_allocation: AllocationId,
 
+    /// All the active allocations.
+    pub allocations: Vec<IndexingAllocation>,
+
     /// The total amount of tokens allocated.
     pub total_allocated_tokens: u128,
 
@@ -91,17 +97,18 @@ pub(super) struct IndexingInfo<P, C> {
     /// See [`IndexingProgress`] for more information.
     pub progress: P, // Freshness<IndexingProgressInfo>,
 
-    /// The indexer fee for this indexing.
-    pub fee: C,
+    /// The indexer cost model for this indexing.
+    pub cost_model: C,
 }
 
 impl From<IndexingRawInfo> for IndexingInfo<(), ()> {
     fn from(raw: IndexingRawInfo) -> Self {
         Self {
             largest_allocation: raw.largest_allocation,
+            allocations: raw.allocations,
             total_allocated_tokens: raw.total_allocated_tokens,
             progress: (),
-            fee: (),
+            cost_model: (),
         }
     }
 }
@@ -115,9 +122,10 @@ impl IndexingInfo<(), ()> {
     ) -> IndexingInfo<IndexingProgress, ()> {
         IndexingInfo {
             largest_allocation: self.largest_allocation,
+            allocations: self.allocations,
             total_allocated_tokens: self.total_allocated_tokens,
             progress,
-            fee: self.fee,
+            cost_model: self.cost_model,
         }
     }
 }
@@ -125,17 +133,21 @@ impl IndexingInfo<(), ()> {
 impl IndexingInfo<IndexingProgress, ()> {
     /// Move the type-state-machine from the partially resolved state to the completely resolved
     /// state by adding the cost model to the indexing information.
-    fn with_fee(self, fee: u128) -> IndexingInfo<IndexingProgress, u128> {
+    fn with_cost_model(
+        self,
+        cost_model: IndexingCostModel,
+    ) -> IndexingInfo<IndexingProgress, IndexingCostModel> {
         IndexingInfo {
             largest_allocation: self.largest_allocation,
+            allocations: self.allocations,
             total_allocated_tokens: self.total_allocated_tokens,
             progress: self.progress,
-            fee,
+            cost_model,
         }
     }
 }
 
-pub(super) type ResolvedIndexingInfo = IndexingInfo<IndexingProgress, u128>;
+pub(super) type ResolvedIndexingInfo = IndexingInfo<IndexingProgress, IndexingCostModel>;
 
 pub(super) type ResolvedIndexerInfo = IndexerInfo<ResolvedIndexingInfo>;
 
@@ -166,13 +178,13 @@ pub(super) async fn process_info(
             tracing::trace!(parent: &indexer_span, "processing");
 
             async move {
-                // Check if the indexer's host is in the host blocklist
+                // Check if the indexer's host is blocked by the host policy
                 //
-                // If the indexer host cannot be resolved or is in the blocklist, the indexer must
-                // be marked as unhealthy
-                if let Err(err) = resolve_and_check_indexer_blocked_by_host_blocklist(
+                // If the indexer host cannot be resolved or is blocked, the indexer must be marked
+                // as unhealthy
+                if let Err(err) = resolve_and_check_indexer_blocked_by_host_policy(
                     &state.indexer_host_resolver,
-                    &state.indexer_host_blocklist,
+                    &state.indexer_host_policy,
                     &indexer.url,
                 )
                 .await
@@ -243,27 +255,22 @@ pub(super) async fn process_info(
     FromIterator::from_iter(processed_info)
 }
 
-/// Resolve and check if the indexer's host is in the host blocklist.
+/// Resolve and check if the indexer's host is blocked by the host policy.
 ///
 /// - If the indexer's host is not resolvable: the indexer is BLOCKED.
-/// - If the host blocklist was not configured: the indexer is ALLOWED.
-/// - If the indexer's host is in the blocklist: the indexer is BLOCKED.
-async fn resolve_and_check_indexer_blocked_by_host_blocklist(
+/// - If the IP blocker DB was not configured: the indexer is ALLOWED.
+/// - If any of the indexer's host addresses matches a deny rule: the indexer is BLOCKED.
+async fn resolve_and_check_indexer_blocked_by_host_policy(
     resolver: &HostResolver,
-    blocklist: &HashSet<IpNetwork>,
+    policy: &HostPolicy,
     url: &Url,
 ) -> Result<(), IndexerInfoResolutionError> {
     // Resolve the indexer's URL, if it fails (or times out), the indexer must be BLOCKED
     let addrs = resolver.resolve_url(url).await?;
 
-    if addrs
-        .iter()
-        .any(|addr| blocklist.iter().any(|net| net.contains(*addr)))
-    {
-        return Err(IndexerInfoResolutionError::BlockedHost);
-    }
-
-    Ok(())
+    policy
+        .check(&addrs)
+        .map_err(|rule| IndexerInfoResolutionError::BlockedHost(rule.to_string()))
 }
 
 /// Resolve and check if the indexer's reported versions are supported.
@@ -333,7 +340,7 @@ async fn process_indexer_indexings(
         Some(blocklist) => {
             for deployment in &blocklist.deployments {
                 indexer_indexings.insert(
-                    *deployment,
+                    deployment.id,
                     Err(IndexingInfoResolutionError::Blocked(
                         blocklist.reason.clone(),
                     )),
@@ -342,6 +349,15 @@ async fn process_indexer_indexings(
         }
     };
 
+    // Block the indexings quarantined for reporting a minority POI
+    for (deployment, entry) in indexer_indexings.iter_mut() {
+        if entry.is_ok() && state.poi_quarantine.contains(url, deployment) {
+            *entry = Err(IndexingInfoResolutionError::Blocked(
+                "POI divergence".to_string(),
+            ));
+        }
+    }
+
     // Keep track of the healthy indexers, so we efficiently resolve the indexer's indexings thar
     // are not marked as unhealthy in a previous resolution step
     let mut healthy_indexer_indexings = indexer_indexings.keys().copied().collect::<Vec<_>>();
@@ -423,8 +439,8 @@ async fn process_indexer_indexings(
                 Ok(info) => info,
                 Err(err) => return (id, Err(err)),
             };
-            let fee = indexer_cost_models.remove(&id).unwrap_or(0);
-            (id, Ok(info.with_fee(fee)))
+            let cost_model = indexer_cost_models.remove(&id).unwrap_or_default();
+            (id, Ok(info.with_cost_model(cost_model)))
         })
         .collect()
 }
@@ -8,7 +8,7 @@ use crate::network::{
     internal::{
         indexer_processing::{IndexerRawInfo, IndexingRawInfo},
         subgraph_processing::{DeploymentRawInfo, SubgraphRawInfo, SubgraphVersionRawInfo},
-        AllocationInfo,
+        AllocationInfo, IndexingAllocation,
     },
     subgraph_client,
     subgraph_client::types::SubgraphVersion,
@@ -77,10 +77,17 @@ pub fn into_internal_indexers_raw_info<'a>(
                 .entry(deployment_id)
                 .or_insert(IndexingRawInfo {
                     largest_allocation: allocation.id,
+                    allocations: vec![],
                     total_allocated_tokens: 0,
                 });
 
             indexing.largest_allocation = indexing_largest_allocation;
+            if indexing.allocations.iter().all(|a| a.id != allocation.id) {
+                indexing.allocations.push(IndexingAllocation {
+                    id: allocation.id,
+                    allocated_tokens: allocation.allocated_tokens,
+                });
+            }
             indexing.total_allocated_tokens = indexing
                 .total_allocated_tokens
                 .saturating_add(allocation.allocated_tokens);
@@ -16,6 +16,7 @@ use url::Url;
 use super::{DeploymentInfo, SubgraphInfo};
 use crate::network::{
     errors::{DeploymentError, IndexerInfoResolutionError, IndexingError, SubgraphError},
+    indexer_indexing_cost_model_resolver::IndexingCostModel,
     internal::indexer_processing::ResolvedIndexerInfo,
 };
 
@@ -34,6 +35,15 @@ pub struct IndexingId {
     pub deployment: DeploymentId,
 }
 
+/// An indexer's active allocation on a deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexingAllocation {
+    /// The allocation address.
+    pub id: AllocationId,
+    /// The amount of tokens allocated.
+    pub allocated_tokens: u128,
+}
+
 #[derive(Debug, Clone)]
 pub struct Indexing {
     /// The indexing unique identifier.
@@ -43,6 +53,8 @@ pub struct Indexing {
     /// This is, among all allocations associated with the indexer and deployment, the address
     /// with the largest amount of allocated tokens.
     pub largest_allocation: AllocationId,
+    /// All the indexer's active allocations on the deployment.
+    pub allocations: Vec<IndexingAllocation>,
     /// The indexer's indexing total allocated tokens.
     ///
     /// This is, the sum of all allocated tokens associated with the indexer and deployment.
@@ -53,8 +65,8 @@ pub struct Indexing {
     ///
     /// See [`IndexingProgress`] for more information.
     pub progress: IndexingProgress,
-    /// The indexer's query fee
-    pub fee: u128,
+    /// The indexer's cost model, used to compute the query fee.
+    pub cost_model: IndexingCostModel,
 }
 
 /// The [`IndexingProgress`] struct represents the progress of an indexing.
@@ -377,20 +389,22 @@ fn construct_indexings_table_row(
 
     // Construct the indexing table row
     let indexing_largest_allocation_addr = indexing_info.largest_allocation;
+    let indexing_allocations = indexing_info.allocations.clone();
     let indexing_total_allocated_tokens = indexing_info.total_allocated_tokens;
     let indexing_progress = indexing_info.progress.to_owned();
-    let fee = indexing_info.fee;
+    let cost_model = indexing_info.cost_model.clone();
 
     let indexing = Indexing {
         id: indexing_id,
         largest_allocation: indexing_largest_allocation_addr,
+        allocations: indexing_allocations,
         total_allocated_tokens: indexing_total_allocated_tokens,
         indexer: Arc::clone(indexer),
         progress: IndexingProgress {
             latest_block: indexing_progress.latest_block,
             min_block: indexing_progress.min_block,
         },
-        fee,
+        cost_model,
     };
 
     (indexing_id, Ok(indexing))
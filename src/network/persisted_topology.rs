@@ -0,0 +1,127 @@
+//! Persistence of the latest network subgraph data.
+//!
+//! The data fetched from the network subgraph is written to a local file after each successful
+//! fetch. On startup, the persisted data is used until the first successful fetch, as long as it is
+//! not older than the configured maximum staleness.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{ensure, Context as _};
+use serde::{Deserialize, Serialize};
+
+use super::subgraph_client::types::Subgraph;
+use crate::{fs::write_atomic, time::unix_timestamp};
+
+/// The persisted network subgraph data.
+#[derive(Deserialize, Serialize)]
+struct PersistedData<S> {
+    /// Unix timestamp (in seconds) of the network subgraph fetch.
+    timestamp: u64,
+    subgraphs: S,
+}
+
+/// A local file holding the latest network subgraph data.
+pub struct PersistedTopology {
+    path: PathBuf,
+    max_staleness: Duration,
+}
+
+impl PersistedTopology {
+    pub fn new(path: PathBuf, max_staleness: Duration) -> Self {
+        Self {
+            path,
+            max_staleness,
+        }
+    }
+
+    /// Write the network subgraph data to the file.
+    pub async fn store(&self, subgraphs: &[Subgraph]) -> anyhow::Result<()> {
+        let data = PersistedData {
+            timestamp: unix_timestamp() / 1_000,
+            subgraphs,
+        };
+        let content = serde_json::to_vec(&data)?;
+        write_atomic(self.path.clone(), content).await
+    }
+
+    /// Load the network subgraph data from the file.
+    ///
+    /// Returns the Unix timestamp (in seconds) of the network subgraph fetch, along with the data.
+    /// If the data is older than the maximum staleness, an error is returned.
+    pub async fn load(&self) -> anyhow::Result<(u64, Vec<Subgraph>)> {
+        let path = self.path.clone();
+        let data = tokio::task::spawn_blocking(move || load_from_file(&path))
+            .await
+            .context("file read task failed")??;
+        let age = (unix_timestamp() / 1_000).saturating_sub(data.timestamp);
+        ensure!(
+            age <= self.max_staleness.as_secs(),
+            "persisted network topology too old ({age}s)"
+        );
+        Ok((data.timestamp, data.subgraphs))
+    }
+}
+
+fn load_from_file(path: &Path) -> anyhow::Result<PersistedData<Vec<Subgraph>>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&content).context("invalid persisted network topology")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use super::{PersistedData, PersistedTopology};
+    use crate::{fs::temp_path, network::subgraph_client::types::Subgraph, time::unix_timestamp};
+
+    #[tokio::test]
+    async fn store_and_load() {
+        //* Given
+        let path = temp_path("topology.json");
+        let persisted = PersistedTopology::new(path.clone(), Duration::from_secs(60));
+
+        //* When
+        let stored = persisted.store(&[]).await;
+        let loaded = persisted.load().await;
+        let _ = std::fs::remove_file(&path);
+
+        //* Then
+        assert!(stored.is_ok());
+        assert_matches!(loaded, Ok((timestamp, subgraphs)) => {
+            assert!(timestamp <= unix_timestamp() / 1_000);
+            assert!(subgraphs.is_empty());
+        });
+    }
+
+    #[tokio::test]
+    async fn reject_stale_data() {
+        //* Given
+        let path = temp_path("topology.json");
+        let data = PersistedData {
+            timestamp: (unix_timestamp() / 1_000) - 120,
+            subgraphs: Vec::<Subgraph>::new(),
+        };
+        std::fs::write(&path, serde_json::to_vec(&data).unwrap()).unwrap();
+
+        //* When
+        let fresh = PersistedTopology::new(path.clone(), Duration::from_secs(300))
+            .load()
+            .await;
+        let stale = PersistedTopology::new(path.clone(), Duration::from_secs(60))
+            .load()
+            .await;
+        let _ = std::fs::remove_file(&path);
+
+        //* Then
+        assert!(fresh.is_ok());
+        assert_matches!(stale, Err(err) => {
+            assert!(err.to_string().starts_with("persisted network topology too old"));
+        });
+    }
+}
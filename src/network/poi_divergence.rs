@@ -0,0 +1,367 @@
+//! Automatic detection of Proof of Indexing (POI) divergences across indexers.
+//!
+//! A background job periodically picks the deployments with the most indexers, fetches the public
+//! POIs of all their indexers at a common block, and groups the indexers by POI. If a strict
+//! majority of the indexers agree on a POI, the indexers reporting a different POI are flagged.
+//!
+//! Flagged indexers are reported as [`PoiDivergence`] events and in the metrics. If enabled, they
+//! are also quarantined, i.e., blocked from serving queries for the deployment, until a later check
+//! finds them in agreement with the majority.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use thegraph_core::{alloy::primitives::BlockNumber, DeploymentId, IndexerId, ProofOfIndexing};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use url::Url;
+
+use super::{indexer_indexing_poi_resolver::PoiResolver, NetworkService};
+use crate::{config::PoiDivergenceConfig, metrics::METRICS};
+
+/// POIs are compared at a block number multiple of this interval, so that the POIs requested to
+/// each indexer stay the same across multiple checks and can be served from the resolver cache.
+const POI_BLOCK_INTERVAL: BlockNumber = 1_000;
+
+/// The minimum number of indexers required to check a deployment.
+const MIN_INDEXERS: usize = 3;
+
+/// A POI divergence detected across the indexers of a deployment.
+#[derive(Clone, Debug, Serialize)]
+pub struct PoiDivergence {
+    pub deployment: DeploymentId,
+    pub block_number: BlockNumber,
+    /// The POI reported by the majority of the indexers.
+    pub majority_poi: String,
+    /// The number of indexers reporting the majority POI.
+    pub majority_indexers: usize,
+    /// The indexers reporting a POI different from the majority.
+    pub minority: Vec<DivergentIndexer>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DivergentIndexer {
+    pub indexer: IndexerId,
+    pub url: String,
+    pub poi: String,
+}
+
+/// The result of a conclusive POI check, i.e. a check where a strict majority of the indexers
+/// agree on a POI.
+struct PoiCheck {
+    /// The indexers reporting the majority POI.
+    majority: Vec<Url>,
+    /// The divergence, if some indexers report a different POI.
+    divergence: Option<PoiDivergence>,
+}
+
+/// The indexers quarantined for reporting a minority POI, per deployment.
+///
+/// This is shared between the POI divergence job, which updates it, and the network topology
+/// updater, which blocks the quarantined indexings.
+#[derive(Clone, Default)]
+pub struct PoiQuarantine(Arc<RwLock<HashMap<DeploymentId, HashMap<Url, IndexerId>>>>);
+
+impl PoiQuarantine {
+    /// Check if the indexer is quarantined for the given deployment.
+    pub fn contains(&self, url: &Url, deployment: &DeploymentId) -> bool {
+        self.0
+            .read()
+            .get(deployment)
+            .map(|indexers| indexers.contains_key(url))
+            .unwrap_or(false)
+    }
+
+    fn get(&self, deployment: &DeploymentId) -> HashMap<Url, IndexerId> {
+        self.0.read().get(deployment).cloned().unwrap_or_default()
+    }
+
+    /// Update the deployment's quarantined indexers with the result of a POI check. The indexers
+    /// reporting the majority POI are released, and the ones reporting a different POI are
+    /// quarantined if `auto_quarantine` is set. Other quarantined indexers, e.g. the ones that did
+    /// not report a POI, stay quarantined.
+    fn update(&self, deployment: DeploymentId, check: &PoiCheck, auto_quarantine: bool) {
+        let mut quarantine = self.0.write();
+        let indexers = quarantine.entry(deployment).or_default();
+        indexers.retain(|url, _| !check.majority.contains(url));
+        if let (true, Some(divergence)) = (auto_quarantine, &check.divergence) {
+            for minority in &divergence.minority {
+                if let Ok(url) = minority.url.parse() {
+                    indexers.insert(url, minority.indexer);
+                }
+            }
+        }
+        if indexers.is_empty() {
+            quarantine.remove(&deployment);
+        }
+    }
+}
+
+/// Spawn the POI divergence detection job.
+pub fn spawn(
+    resolver: PoiResolver,
+    network: NetworkService,
+    conf: PoiDivergenceConfig,
+    quarantine: PoiQuarantine,
+    events: mpsc::UnboundedSender<PoiDivergence>,
+) {
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(conf.interval);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+
+            let mut deployments: Vec<(DeploymentId, Vec<(IndexerId, Url, BlockNumber)>)> =
+                network.deployment_indexings().into_iter().collect();
+            deployments.sort_by_key(|(_, indexings)| std::cmp::Reverse(indexings.len()));
+            deployments.truncate(conf.deployments);
+
+            METRICS.poi_divergence.reset();
+            for (deployment, indexings) in deployments {
+                let divergence = check_and_quarantine(
+                    &resolver,
+                    &quarantine,
+                    deployment,
+                    indexings,
+                    conf.auto_quarantine,
+                );
+                let divergence = match divergence.await {
+                    Some(divergence) => divergence,
+                    None => continue,
+                };
+
+                tracing::warn!(
+                    %deployment,
+                    block_number = divergence.block_number,
+                    minority = ?divergence.minority,
+                    "POI divergence"
+                );
+                METRICS
+                    .poi_divergence
+                    .with_label_values(&[&deployment.to_string()])
+                    .set(divergence.minority.len() as i64);
+                let _ = events.send(divergence);
+            }
+        }
+    });
+}
+
+/// Check the deployment, and update its quarantined indexers. Returns the divergence, if any.
+///
+/// Inconclusive checks leave the quarantined indexers unchanged.
+async fn check_and_quarantine(
+    resolver: &PoiResolver,
+    quarantine: &PoiQuarantine,
+    deployment: DeploymentId,
+    indexings: Vec<(IndexerId, Url, BlockNumber)>,
+    auto_quarantine: bool,
+) -> Option<PoiDivergence> {
+    let check = check_deployment(resolver, quarantine, deployment, indexings).await?;
+    quarantine.update(deployment, &check, auto_quarantine);
+    check.divergence
+}
+
+/// Check the POIs of the deployment's indexers, including the quarantined ones.
+///
+/// Returns `None` if the check is inconclusive, e.g. if there are not enough indexers, or if no
+/// strict majority of the indexers agree on a POI.
+async fn check_deployment(
+    resolver: &PoiResolver,
+    quarantine: &PoiQuarantine,
+    deployment: DeploymentId,
+    indexings: Vec<(IndexerId, Url, BlockNumber)>,
+) -> Option<PoiCheck> {
+    // Compare the POIs at a block indexed by all the available indexers
+    let latest_block = indexings.iter().map(|(_, _, block)| *block).min()?;
+    let block_number = latest_block - (latest_block % POI_BLOCK_INTERVAL);
+    if block_number == 0 {
+        return None;
+    }
+
+    let mut indexers: HashMap<Url, IndexerId> = quarantine.get(&deployment);
+    indexers.extend(indexings.into_iter().map(|(id, url, _)| (url, id)));
+    if indexers.len() < MIN_INDEXERS {
+        return None;
+    }
+
+    let requests = indexers.iter().map(|(url, indexer)| async move {
+        let mut pois = resolver.resolve(url, &[(deployment, block_number)]).await;
+        let poi = pois.remove(&(deployment, block_number))?;
+        Some((*indexer, url.clone(), poi))
+    });
+    let pois: Vec<(IndexerId, Url, ProofOfIndexing)> = futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let (majority_poi, majority_indexers) = majority(pois.iter().map(|(_, _, poi)| *poi))?;
+    let (majority, minority): (Vec<_>, Vec<_>) = pois
+        .into_iter()
+        .partition(|(_, _, poi)| *poi == majority_poi);
+    let divergence = (!minority.is_empty()).then(|| PoiDivergence {
+        deployment,
+        block_number,
+        majority_poi: majority_poi.to_string(),
+        majority_indexers,
+        minority: minority
+            .into_iter()
+            .map(|(indexer, url, poi)| DivergentIndexer {
+                indexer,
+                url: url.to_string(),
+                poi: poi.to_string(),
+            })
+            .collect(),
+    });
+    Some(PoiCheck {
+        majority: majority.into_iter().map(|(_, url, _)| url).collect(),
+        divergence,
+    })
+}
+
+/// Returns the POI reported by a strict majority, along with the number of indexers reporting it.
+fn majority(pois: impl IntoIterator<Item = ProofOfIndexing>) -> Option<(ProofOfIndexing, usize)> {
+    let mut groups: Vec<(ProofOfIndexing, usize)> = Vec::new();
+    let mut total = 0;
+    for poi in pois {
+        total += 1;
+        match groups.iter_mut().find(|(group_poi, _)| *group_poi == poi) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((poi, 1)),
+        }
+    }
+    groups
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .filter(|(_, count)| (count * 2) > total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use thegraph_core::{
+        alloy::primitives::{Address, B256},
+        DeploymentId, IndexerId, ProofOfIndexing,
+    };
+    use url::Url;
+
+    use super::{
+        check_and_quarantine, majority, DivergentIndexer, PoiCheck, PoiDivergence, PoiQuarantine,
+    };
+    use crate::network::indexer_indexing_poi_resolver::PoiResolver;
+
+    fn poi(n: u8) -> ProofOfIndexing {
+        B256::repeat_byte(n).into()
+    }
+
+    fn indexer(n: u8) -> (Url, IndexerId) {
+        let url = format!("https://{n}.example.com/").parse().unwrap();
+        (url, Address::repeat_byte(n).into())
+    }
+
+    fn check(majority: &[u8], minority: &[u8]) -> PoiCheck {
+        PoiCheck {
+            majority: majority.iter().map(|n| indexer(*n).0).collect(),
+            divergence: (!minority.is_empty()).then(|| PoiDivergence {
+                deployment: DeploymentId::new([1; 32].into()),
+                block_number: 1_000,
+                majority_poi: poi(1).to_string(),
+                majority_indexers: majority.len(),
+                minority: minority
+                    .iter()
+                    .map(|n| {
+                        let (url, indexer) = indexer(*n);
+                        DivergentIndexer {
+                            indexer,
+                            url: url.to_string(),
+                            poi: poi(2).to_string(),
+                        }
+                    })
+                    .collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn update_quarantine() {
+        //* Given
+        let deployment = DeploymentId::new([1; 32].into());
+        let quarantine = PoiQuarantine::default();
+
+        //* When
+        quarantine.update(deployment, &check(&[1, 2, 3], &[4, 5]), true);
+        let quarantined = quarantine.get(&deployment);
+        // Indexer 5 did not report a POI
+        quarantine.update(deployment, &check(&[1, 2, 3, 4], &[]), true);
+
+        //* Then
+        assert_eq!(quarantined.len(), 2);
+        assert!(!quarantine.contains(&indexer(4).0, &deployment));
+        assert!(quarantine.contains(&indexer(5).0, &deployment));
+    }
+
+    #[tokio::test]
+    async fn inconclusive_check_keeps_quarantine() {
+        //* Given
+        let resolver = PoiResolver::new(
+            reqwest::Client::new(),
+            Duration::from_secs(5),
+            Duration::from_secs(60),
+        );
+        let deployment = DeploymentId::new([1; 32].into());
+        let quarantine = PoiQuarantine::default();
+        quarantine.update(deployment, &check(&[1, 2, 3], &[4]), true);
+        let indexing = |n: u8, block| {
+            let (url, indexer) = indexer(n);
+            (indexer, url, block)
+        };
+
+        //* When
+        // Not enough indexers
+        let too_few = check_and_quarantine(
+            &resolver,
+            &quarantine,
+            deployment,
+            vec![indexing(1, 10_000)],
+            true,
+        )
+        .await;
+        // No block to compare the POIs at
+        let too_early = check_and_quarantine(
+            &resolver,
+            &quarantine,
+            deployment,
+            vec![indexing(1, 500), indexing(2, 10_000), indexing(3, 10_000)],
+            true,
+        )
+        .await;
+
+        //* Then
+        assert!(too_few.is_none());
+        assert!(too_early.is_none());
+        assert!(quarantine.contains(&indexer(4).0, &deployment));
+    }
+
+    #[test]
+    fn update_quarantine_without_auto_quarantine() {
+        //* Given
+        let deployment = DeploymentId::new([1; 32].into());
+        let quarantine = PoiQuarantine::default();
+
+        //* When
+        quarantine.update(deployment, &check(&[1, 2, 3], &[4]), false);
+
+        //* Then
+        assert!(quarantine.get(&deployment).is_empty());
+        assert!(quarantine.0.read().is_empty());
+    }
+
+    #[test]
+    fn strict_majority() {
+        assert_eq!(majority([poi(1), poi(1), poi(2)]), Some((poi(1), 2)));
+        assert_eq!(majority([poi(1), poi(1), poi(2), poi(2)]), None);
+        assert_eq!(majority([poi(1), poi(2), poi(3)]), None);
+        assert_eq!(majority([]), None);
+    }
+}
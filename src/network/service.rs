@@ -4,34 +4,49 @@
 
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
     time::Duration,
 };
 
-use ipnetwork::IpNetwork;
 use semver::Version;
 use thegraph_core::{
     alloy::primitives::{Address, BlockNumber},
-    DeploymentId, SubgraphId,
+    AllocationId, DeploymentId, IndexerId, SubgraphId,
 };
-use tokio::{sync::watch, time::MissedTickBehavior};
+use tokio::{
+    sync::{broadcast, watch},
+    time::{Instant, MissedTickBehavior},
+};
+use url::Url;
 
 use super::{
+    closed_allocations::ClosedAllocations,
     config::VersionRequirements,
     errors::{DeploymentError, SubgraphError},
+    indexer_blocklist::IndexerBlocklist,
+    indexer_host_policy::HostPolicy,
     indexer_host_resolver::HostResolver,
     indexer_indexing_cost_model_resolver::CostModelResolver,
     indexer_indexing_poi_blocklist::PoiBlocklist,
     indexer_indexing_poi_resolver::PoiResolver,
-    indexer_indexing_progress_resolver::IndexingProgressResolver,
+    indexer_indexing_progress_resolver::{AdaptiveTimeout, IndexingProgressResolver},
     indexer_version_resolver::VersionResolver,
     internal::{
-        fetch_and_preprocess_subgraph_info, fetch_update, Indexing, IndexingId, InternalState,
-        NetworkTopologySnapshot, PreprocessedNetworkInfo,
+        fetch_subgraph_info, fetch_update, preprocess_subgraph_info, Indexing, IndexingId,
+        InternalState, NetworkTopologySnapshot, PreprocessedNetworkInfo,
     },
+    persisted_topology::PersistedTopology,
+    poi_divergence::PoiQuarantine,
+    static_topology,
     subgraph_client::Client as SubgraphClient,
+    topology_events::{self, TopologyEvent},
     ResolutionError,
 };
-use crate::config::{BlockedIndexer, BlockedPoi};
+use crate::{
+    config::{BlockedIndexer, BlockedPoi, NetworkConfig},
+    metrics::METRICS,
+    time::unix_timestamp,
+};
 
 /// Subgraph resolution information returned by the [`NetworkService`].
 pub struct ResolvedSubgraphInfo {
@@ -74,6 +89,7 @@ impl ResolvedSubgraphInfo {
 #[derive(Clone)]
 pub struct NetworkService {
     network: watch::Receiver<NetworkTopologySnapshot>,
+    closed_allocations: ClosedAllocations,
 }
 
 impl NetworkService {
@@ -113,7 +129,12 @@ impl NetworkService {
             .indexings
             .clone()
             .into_iter()
-            .map(|(id, res)| (id, res.map_err(|err| err.into())))
+            .map(|(id, res)| {
+                let res = self
+                    .closed_allocations
+                    .filter(res.map_err(|err| err.into()));
+                (id, res)
+            })
             .collect();
 
         Ok(Some(ResolvedSubgraphInfo {
@@ -149,7 +170,12 @@ impl NetworkService {
             .indexings
             .clone()
             .into_iter()
-            .map(|(id, res)| (id, res.map_err(|err| err.into())))
+            .map(|(id, res)| {
+                let res = self
+                    .closed_allocations
+                    .filter(res.map_err(|err| err.into()));
+                (id, res)
+            })
             .collect();
 
         Ok(Some(ResolvedSubgraphInfo {
@@ -161,6 +187,14 @@ impl NetworkService {
         }))
     }
 
+    /// Mark the indexer's allocation as closed, after the indexer rejected a receipt for it.
+    ///
+    /// The allocation is no longer used to issue receipts, and an out-of-cycle network topology
+    /// update is requested.
+    pub fn report_closed_allocation(&self, indexer: IndexerId, allocation: AllocationId) {
+        self.closed_allocations.insert(indexer, allocation);
+    }
+
     /// Get the latest indexed block number reported by the indexers.
     pub fn indexing_progress(&self) -> HashMap<IndexingId, BlockNumber> {
         self.network
@@ -171,72 +205,218 @@ impl NetworkService {
             .flat_map(|(id, indexing)| indexing.iter().map(|i| (*id, i.progress.latest_block)))
             .collect()
     }
+
+    /// Get the available indexings of each deployment, along with the indexer URL and the latest
+    /// indexed block number.
+    pub fn deployment_indexings(
+        &self,
+    ) -> HashMap<DeploymentId, Vec<(IndexerId, Url, BlockNumber)>> {
+        self.network
+            .borrow()
+            .deployments
+            .iter()
+            .filter_map(|(id, result)| Some((*id, result.as_ref().ok()?)))
+            .map(|(id, deployment)| {
+                let indexings = deployment
+                    .indexings
+                    .values()
+                    .filter_map(|indexing| indexing.as_ref().ok())
+                    .map(|indexing| {
+                        (
+                            indexing.id.indexer,
+                            indexing.indexer.url.clone(),
+                            indexing.progress.latest_block,
+                        )
+                    })
+                    .collect();
+                (id, indexings)
+            })
+            .collect()
+    }
+}
+
+/// The source of the network topology information.
+pub enum TopologySource {
+    /// The graph network subgraph, queried via the trusted indexers.
+    NetworkSubgraph(SubgraphClient),
+    /// A static topology file, see [`static_topology`].
+    StaticFile(PathBuf),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn spawn(
     http_client: reqwest::Client,
-    subgraph_client: SubgraphClient,
+    topology_source: TopologySource,
     min_indexer_service_version: Version,
     min_graph_node_version: Version,
     indexer_blocklist: BTreeMap<Address, BlockedIndexer>,
-    indexer_host_blocklist: HashSet<IpNetwork>,
+    indexer_host_policy: HostPolicy,
     poi_blocklist: Vec<BlockedPoi>,
+    poi_quarantine: PoiQuarantine,
+    persisted_topology: Option<PersistedTopology>,
+    conf: NetworkConfig,
+    topology_events: broadcast::Sender<TopologyEvent>,
 ) -> NetworkService {
+    let adaptive_timeout = conf.adaptive_timeout_factor.map(|factor| AdaptiveTimeout {
+        factor,
+        min: conf.adaptive_timeout_min,
+    });
     let internal_state = InternalState {
-        indexer_blocklist,
-        indexer_host_resolver: HostResolver::new(Duration::from_secs(5))
-            .expect("failed to create host resolver"),
-        indexer_host_blocklist,
+        indexer_blocklist: IndexerBlocklist::new(indexer_blocklist),
+        indexer_host_resolver: HostResolver::new(
+            conf.host_resolution_timeout,
+            conf.host_resolution_min_ttl,
+            conf.host_resolution_max_ttl,
+            conf.host_resolution_negative_ttl,
+        )
+        .expect("failed to create host resolver"),
+        indexer_host_policy,
         indexer_version_requirements: VersionRequirements {
             min_indexer_service_version,
             min_graph_node_version,
         },
-        indexer_version_resolver: VersionResolver::new(http_client.clone(), Duration::from_secs(5)),
+        indexer_version_resolver: VersionResolver::new(http_client.clone(), conf.version_timeout),
         poi_blocklist: PoiBlocklist::new(poi_blocklist),
-        poi_resolver: PoiResolver::new(
+        poi_quarantine,
+        poi_resolver: PoiResolver::new(http_client.clone(), conf.poi_timeout, conf.poi_cache_ttl),
+        indexing_progress_resolver: IndexingProgressResolver::new(
             http_client.clone(),
-            Duration::from_secs(5),
-            Duration::from_secs(20 * 60),
+            conf.indexing_progress_timeout,
+            adaptive_timeout,
         ),
-        indexing_progress_resolver: IndexingProgressResolver::new(
+        cost_model_resolver: CostModelResolver::new(
             http_client.clone(),
-            Duration::from_secs(25),
+            conf.cost_model_timeout,
+            conf.cost_model_max_age,
         ),
-        cost_model_resolver: CostModelResolver::new(http_client.clone(), Duration::from_secs(5)),
     };
-    let update_interval = Duration::from_secs(60);
-    let network = spawn_updater_task(subgraph_client, internal_state, update_interval);
+    let closed_allocations = ClosedAllocations::default();
+    let network = spawn_updater_task(
+        topology_source,
+        internal_state,
+        persisted_topology,
+        conf.update_interval,
+        topology_events,
+        closed_allocations.clone(),
+    );
 
-    NetworkService { network }
+    NetworkService {
+        network,
+        closed_allocations,
+    }
 }
 
-/// Spawn a background task to fetch the network topology information from the graph network
-/// subgraph at regular intervals
+/// The minimum interval between out-of-cycle network topology updates.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Spawn a background task to fetch the network topology information from the given source at
+/// regular intervals.
+///
+/// If a [`PersistedTopology`] is given, the fetched data is persisted after each successful fetch.
+/// The persisted data is only used until the first successful fetch, on cold starts with an
+/// unavailable network subgraph.
+///
+/// The changes between consecutive snapshots are published as [`TopologyEvent`]s.
+///
+/// Reports of closed allocations trigger an out-of-cycle update, at most once per
+/// [`MIN_REFRESH_INTERVAL`]. The reported allocations still active in the data fetched after a
+/// grace period are unmarked, see [`ClosedAllocations::unmark_active`].
 fn spawn_updater_task(
-    mut subgraph_client: SubgraphClient,
-    state: InternalState,
+    mut topology_source: TopologySource,
+    mut state: InternalState,
+    persisted_topology: Option<PersistedTopology>,
     update_interval: Duration,
+    events: broadcast::Sender<TopologyEvent>,
+    closed_allocations: ClosedAllocations,
 ) -> watch::Receiver<NetworkTopologySnapshot> {
     let (tx, rx) = watch::channel(Default::default());
 
     tokio::spawn(async move {
         let mut network_info: Option<PreprocessedNetworkInfo> = None;
+        // Whether a snapshot was published, i.e. if there is a previous snapshot to diff against
+        let mut published = false;
+        // Unix timestamp (in seconds) of the network subgraph data in use
+        let mut network_info_timestamp: u64 = 0;
 
         let mut timer = tokio::time::interval(update_interval);
         timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_update = Instant::now();
         loop {
-            timer.tick().await;
+            // Start time of this iteration's successful fetch, if any
+            let mut fetched_at: Option<Instant> = None;
+            tokio::select! {
+                _ = timer.tick() => (),
+                _ = closed_allocations.refresh_requested() => {
+                    tokio::time::sleep_until(last_update + MIN_REFRESH_INTERVAL).await;
+                    tracing::info!("network topology update requested");
+                    timer.reset();
+                }
+            }
+            last_update = Instant::now();
 
-            match fetch_and_preprocess_subgraph_info(&mut subgraph_client, update_interval).await {
-                Ok(info) => network_info = Some(info),
-                Err(network_subgraph_update_err) => tracing::error!(%network_subgraph_update_err),
+            let data = match &mut topology_source {
+                TopologySource::NetworkSubgraph(client) => {
+                    fetch_subgraph_info(client, update_interval).await
+                }
+                TopologySource::StaticFile(path) => {
+                    static_topology::load_from_file(path.clone()).await
+                }
+            };
+            match data {
+                Ok(data) => {
+                    if let Some(persisted_topology) = &persisted_topology {
+                        if let Err(persist_topology_err) = persisted_topology.store(&data).await {
+                            tracing::error!(%persist_topology_err);
+                        }
+                    }
+                    network_info = Some(preprocess_subgraph_info(data));
+                    network_info_timestamp = unix_timestamp() / 1_000;
+                    fetched_at = Some(last_update);
+                }
+                Err(network_subgraph_update_err) => {
+                    tracing::error!(%network_subgraph_update_err);
+                    if let (None, Some(persisted_topology)) = (&network_info, &persisted_topology) {
+                        match persisted_topology.load().await {
+                            Ok((timestamp, data)) => {
+                                tracing::warn!(timestamp, "using persisted network topology");
+                                network_info = Some(preprocess_subgraph_info(data));
+                                network_info_timestamp = timestamp;
+                            }
+                            Err(load_topology_err) => tracing::error!(%load_topology_err),
+                        }
+                    }
+                }
             };
             let network_info = match &network_info {
                 Some(info) => info,
                 None => continue,
             };
-            let snapshot = fetch_update(network_info, &state).await;
+            let network_info_age =
+                (unix_timestamp() / 1_000).saturating_sub(network_info_timestamp);
+            METRICS
+                .network_topology_age_seconds
+                .set(network_info_age as i64);
+            let snapshot = fetch_update(network_info, &mut state).await;
+            if let Some(fetched_at) = fetched_at {
+                let active_allocations: HashSet<AllocationId> = snapshot
+                    .deployments
+                    .values()
+                    .filter_map(|d| d.as_ref().ok())
+                    .flat_map(|d| d.indexings.values())
+                    .filter_map(|i| i.as_ref().ok())
+                    .flat_map(|i| i.allocations.iter().map(|a| a.id))
+                    .collect();
+                closed_allocations.unmark_active(fetched_at.into_std(), |allocation| {
+                    active_allocations.contains(allocation)
+                });
+            }
+            let changes = if published {
+                topology_events::diff(&tx.borrow(), &snapshot)
+            } else {
+                vec![]
+            };
             tracing::info!(
+                changes = changes.len(),
                 subgraphs = snapshot.subgraphs.len(),
                 deployments = snapshot.deployments.len(),
                 indexings = snapshot
@@ -248,6 +428,11 @@ fn spawn_updater_task(
             );
 
             let _ = tx.send(snapshot);
+            published = true;
+            for event in changes {
+                // An error only means there are no subscribers at the moment
+                let _ = events.send(event);
+            }
         }
     });
 
@@ -0,0 +1,271 @@
+//! Static network topology, loaded from a JSON file.
+//!
+//! This is an alternative to the network subgraph, meant for running the gateway against local
+//! indexer-services without a chain or a network subgraph. The file is read on every network
+//! topology update, so changes to it are picked up without restarting the gateway.
+//!
+//! Example:
+//!
+//! ```json
+//! {
+//!   "indexers": [
+//!     { "id": "0x0000000000000000000000000000000000000001", "url": "http://localhost:7600" }
+//!   ],
+//!   "deployments": [
+//!     {
+//!       "id": "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz",
+//!       "network": "mainnet",
+//!       "allocations": [
+//!         {
+//!           "id": "0x0000000000000000000000000000000000000002",
+//!           "indexer": "0x0000000000000000000000000000000000000001"
+//!         }
+//!       ]
+//!     }
+//!   ],
+//!   "subgraphs": [
+//!     {
+//!       "id": "21dvLsEbzPDqCVn5zT6pAXoyoNB4dLmMCHZCdMdFw8WR",
+//!       "versions": ["QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz"]
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context as _};
+use serde::Deserialize;
+use thegraph_core::{
+    alloy::primitives::BlockNumber, AllocationId, DeploymentId, IndexerId, SubgraphId,
+};
+
+use super::subgraph_client::types::{
+    Allocation, Indexer, Manifest, Subgraph, SubgraphDeployment, SubgraphVersion,
+};
+
+#[derive(Deserialize)]
+struct StaticTopology {
+    indexers: Vec<StaticIndexer>,
+    deployments: Vec<StaticDeployment>,
+    subgraphs: Vec<StaticSubgraph>,
+}
+
+#[derive(Deserialize)]
+struct StaticIndexer {
+    id: IndexerId,
+    url: String,
+    #[serde(default)]
+    staked_tokens: u128,
+}
+
+#[derive(Deserialize)]
+struct StaticDeployment {
+    id: DeploymentId,
+    network: String,
+    #[serde(default)]
+    start_block: BlockNumber,
+    allocations: Vec<StaticAllocation>,
+}
+
+#[derive(Deserialize)]
+struct StaticAllocation {
+    id: AllocationId,
+    indexer: IndexerId,
+    #[serde(default = "default_allocated_tokens")]
+    allocated_tokens: u128,
+}
+
+fn default_allocated_tokens() -> u128 {
+    1
+}
+
+#[derive(Deserialize)]
+struct StaticSubgraph {
+    id: SubgraphId,
+    /// Subgraph versions, in ascending order.
+    versions: Vec<DeploymentId>,
+}
+
+/// Load the network topology from a JSON file, in the same representation as the network subgraph
+/// data. As in the network subgraph data, the subgraph versions are in descending order.
+///
+/// The file is read and parsed on a blocking thread, to avoid blocking the async runtime.
+pub async fn load_from_file(path: PathBuf) -> anyhow::Result<Vec<Subgraph>> {
+    tokio::task::spawn_blocking(move || load_from_file_blocking(&path))
+        .await
+        .context("file read task failed")?
+}
+
+fn load_from_file_blocking(path: &Path) -> anyhow::Result<Vec<Subgraph>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let topology: StaticTopology =
+        serde_json::from_str(&content).context("invalid static topology")?;
+    into_subgraphs(topology)
+}
+
+fn into_subgraphs(topology: StaticTopology) -> anyhow::Result<Vec<Subgraph>> {
+    let indexers: HashMap<IndexerId, Indexer> = topology
+        .indexers
+        .into_iter()
+        .map(|indexer| {
+            let info = Indexer {
+                id: indexer.id,
+                url: Some(indexer.url),
+                staked_tokens: indexer.staked_tokens,
+            };
+            (indexer.id, info)
+        })
+        .collect();
+
+    let deployments: HashMap<DeploymentId, SubgraphDeployment> = topology
+        .deployments
+        .into_iter()
+        .map(|deployment| {
+            let allocations = deployment
+                .allocations
+                .into_iter()
+                .map(|allocation| {
+                    let indexer = indexers
+                        .get(&allocation.indexer)
+                        .ok_or_else(|| anyhow!("unknown indexer {}", allocation.indexer))?;
+                    Ok(Allocation {
+                        id: allocation.id,
+                        allocated_tokens: allocation.allocated_tokens,
+                        indexer: indexer.clone(),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let info = SubgraphDeployment {
+                id: deployment.id,
+                manifest: Some(Manifest {
+                    network: Some(deployment.network),
+                    start_block: deployment.start_block,
+                }),
+                allocations,
+            };
+            Ok((deployment.id, info))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    topology
+        .subgraphs
+        .into_iter()
+        .map(|subgraph| {
+            // The versions are numbered in file order, and emitted newest first
+            // See ref: 9936786a-e286-45f3-9190-8409d8389e88
+            let versions = subgraph
+                .versions
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(version, deployment)| {
+                    let deployment = deployments
+                        .get(deployment)
+                        .ok_or_else(|| anyhow!("unknown deployment {deployment}"))?;
+                    Ok(SubgraphVersion {
+                        version: version as u32,
+                        subgraph_deployment: deployment.clone(),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(Subgraph {
+                id: subgraph.id,
+                versions,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::{into_subgraphs, StaticTopology};
+
+    #[test]
+    fn convert_static_topology() {
+        //* Given
+        let topology: StaticTopology = serde_json::from_str(
+            r#"{
+                "indexers": [
+                    { "id": "0x0000000000000000000000000000000000000001", "url": "http://localhost:7600" }
+                ],
+                "deployments": [
+                    {
+                        "id": "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz",
+                        "network": "mainnet",
+                        "start_block": 10,
+                        "allocations": [
+                            {
+                                "id": "0x0000000000000000000000000000000000000002",
+                                "indexer": "0x0000000000000000000000000000000000000001"
+                            }
+                        ]
+                    }
+                ],
+                "subgraphs": [
+                    {
+                        "id": "21dvLsEbzPDqCVn5zT6pAXoyoNB4dLmMCHZCdMdFw8WR",
+                        "versions": ["QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz"]
+                    }
+                ]
+            }"#,
+        )
+        .expect("invalid static topology");
+
+        //* When
+        let subgraphs = into_subgraphs(topology);
+
+        //* Then
+        let subgraphs = subgraphs.expect("conversion failed");
+        assert_eq!(subgraphs.len(), 1);
+        let deployment = &subgraphs[0].versions[0].subgraph_deployment;
+        assert_eq!(
+            deployment
+                .manifest
+                .as_ref()
+                .and_then(|m| m.network.as_deref()),
+            Some("mainnet")
+        );
+        assert_eq!(deployment.allocations.len(), 1);
+        assert_eq!(
+            deployment.allocations[0].indexer.url.as_deref(),
+            Some("http://localhost:7600")
+        );
+    }
+
+    #[test]
+    fn reject_unknown_indexer() {
+        //* Given
+        let topology: StaticTopology = serde_json::from_str(
+            r#"{
+                "indexers": [],
+                "deployments": [
+                    {
+                        "id": "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz",
+                        "network": "mainnet",
+                        "allocations": [
+                            {
+                                "id": "0x0000000000000000000000000000000000000002",
+                                "indexer": "0x0000000000000000000000000000000000000001"
+                            }
+                        ]
+                    }
+                ],
+                "subgraphs": []
+            }"#,
+        )
+        .expect("invalid static topology");
+
+        //* When
+        let subgraphs = into_subgraphs(topology);
+
+        //* Then
+        assert_matches!(subgraphs, Err(_));
+    }
+}
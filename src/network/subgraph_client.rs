@@ -4,12 +4,20 @@
 //! This module contains the logic necessary to query the Graph to get the latest state of the
 //! network subgraph.
 
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
 use anyhow::{anyhow, bail, ensure, Context};
 use custom_debug::CustomDebug;
-use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use serde::{de::DeserializeOwned, ser::SerializeMap, Deserialize, Serialize, Serializer};
 use serde_json::json;
 use serde_with::serde_as;
-use thegraph_core::alloy::primitives::{BlockHash, BlockNumber, BlockTimestamp};
+use thegraph_core::{
+    alloy::primitives::{BlockHash, BlockNumber, BlockTimestamp},
+    DeploymentId, IndexerId, SubgraphId,
+};
 use thegraph_graphql_http::http::response::Error as GqlError;
 use types::Subgraph;
 use url::Url;
@@ -17,6 +25,7 @@ use url::Url;
 use crate::{
     blocks::Block,
     indexer_client::{IndexerAuth, IndexerClient},
+    metrics::{with_metric, METRICS},
     time::unix_timestamp,
 };
 
@@ -31,20 +40,20 @@ use crate::{
 ///
 /// See: https://github.com/graphprotocol/graph-network-subgraph/blob/master/schema.graphql
 pub mod types {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
     use serde_with::serde_as;
     use thegraph_core::{
         alloy::primitives::BlockNumber, AllocationId, DeploymentId, IndexerId, SubgraphId,
     };
 
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Subgraph {
         pub id: SubgraphId,
         pub versions: Vec<SubgraphVersion>,
     }
 
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct SubgraphVersion {
         pub version: u32,
@@ -52,7 +61,7 @@ pub mod types {
     }
 
     #[serde_as]
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Manifest {
         pub network: Option<String>,
@@ -60,7 +69,7 @@ pub mod types {
         pub start_block: BlockNumber,
     }
 
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct SubgraphDeployment {
         #[serde(rename = "ipfsHash")]
@@ -71,7 +80,7 @@ pub mod types {
     }
 
     #[serde_as]
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Allocation {
         pub id: AllocationId,
@@ -81,7 +90,7 @@ pub mod types {
     }
 
     #[serde_as]
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Indexer {
         pub id: IndexerId,
@@ -102,9 +111,14 @@ pub struct TrustedIndexer {
     pub auth: String,
 }
 
+/// Number of blocks behind the head of the primary trusted indexer at which the quorum is checked.
+/// This lets trusted indexers lagging slightly behind the primary take part in the quorum.
+const QUORUM_BLOCK_LAG: BlockNumber = 10;
+
 #[derive(Clone, Debug)]
 enum BlockHeight {
     Hash(BlockHash),
+    Number(BlockNumber),
     NumberGte(BlockNumber),
 }
 
@@ -113,83 +127,296 @@ impl Serialize for BlockHeight {
         let mut obj = s.serialize_map(Some(1))?;
         match self {
             Self::Hash(hash) => obj.serialize_entry("hash", hash)?,
+            Self::Number(number) => obj.serialize_entry("number", number)?,
             Self::NumberGte(number) => obj.serialize_entry("number_gte", number)?,
         }
         obj.end()
     }
 }
 
+const HEAD_QUERY: &str = r#"
+    query {
+        meta: _meta { block { number hash timestamp } }
+    }"#;
+
+// ref: 9936786a-e286-45f3-9190-8409d8389e88
+const SUBGRAPHS_QUERY: &str = r#"
+    query ($block: Block_height!, $first: Int!, $where: Subgraph_filter!) {
+        meta: _meta(block: $block) { block { number hash timestamp } }
+        results: subgraphs(
+            block: $block
+            orderBy: id, orderDirection: asc
+            first: $first
+            where: $where
+        ) {
+            entityId: id
+            id
+            active
+            versions(orderBy: version, orderDirection: desc) {
+                version
+                subgraphDeployment {
+                    ipfsHash
+                    manifest {
+                        network
+                        startBlock
+                    }
+                    indexerAllocations(
+                        first: 100
+                        orderBy: allocatedTokens, orderDirection: desc
+                        where: { status: Active }
+                    ) {
+                        id
+                        allocatedTokens
+                        indexer {
+                            id
+                            url
+                            stakedTokens
+                        }
+                    }
+                }
+            }
+        }
+    }"#;
+
+const DEPLOYMENTS_QUERY: &str = r#"
+    query ($block: Block_height!, $first: Int!, $where: SubgraphDeployment_filter!) {
+        meta: _meta(block: $block) { block { number hash timestamp } }
+        results: subgraphDeployments(
+            block: $block
+            orderBy: id, orderDirection: asc
+            first: $first
+            where: $where
+        ) {
+            entityId: id
+            ipfsHash
+            manifest {
+                network
+                startBlock
+            }
+            indexerAllocations(
+                first: 100
+                orderBy: allocatedTokens, orderDirection: desc
+                where: { status: Active }
+            ) {
+                id
+                allocatedTokens
+                indexer {
+                    id
+                    url
+                    stakedTokens
+                }
+            }
+        }
+    }"#;
+
+const INDEXERS_QUERY: &str = r#"
+    query ($block: Block_height!, $first: Int!, $where: Indexer_filter!) {
+        meta: _meta(block: $block) { block { number hash timestamp } }
+        results: indexers(
+            block: $block
+            orderBy: id, orderDirection: asc
+            first: $first
+            where: $where
+        ) {
+            entityId: id
+            id
+            url
+            stakedTokens
+        }
+    }"#;
+
+/// A network subgraph entity, along with its entity ID used for pagination.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Paginated<T> {
+    entity_id: String,
+    #[serde(flatten)]
+    entity: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct Meta {
+    block: PartialBlock,
+}
+
+#[derive(Debug, Deserialize)]
+struct PartialBlock {
+    number: BlockNumber,
+    hash: BlockHash,
+    timestamp: Option<BlockTimestamp>,
+}
+
+impl TryFrom<PartialBlock> for Block {
+    type Error = anyhow::Error;
+    fn try_from(block: PartialBlock) -> anyhow::Result<Self> {
+        Ok(Block {
+            number: block.number,
+            hash: block.hash,
+            timestamp: block
+                .timestamp
+                .ok_or_else(|| anyhow!("response missing block timestamp"))?,
+        })
+    }
+}
+
+/// A subgraph, as returned by the [`SUBGRAPHS_QUERY`].
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+struct SubgraphEntity {
+    active: bool,
+    #[serde(flatten)]
+    subgraph: Subgraph,
+}
+
 /// The Graph network subgraph client.
+///
+/// The client keeps a copy of the subgraphs fetched from the network subgraph. After an initial
+/// full sync, only the entities changed since the latest block are fetched. A full sync is
+/// performed again after `full_sync_interval`, or if an incremental sync fails.
+///
+/// Optionally, the client cross-verifies the fetched entities against multiple trusted indexers
+/// (see [`Client::new`]).
 pub struct Client {
-    pub client: IndexerClient,
-    pub indexers: Vec<TrustedIndexer>,
-    pub page_size: usize,
-    pub latest_block: Option<Block>,
+    client: IndexerClient,
+    indexers: Vec<TrustedIndexer>,
+    page_size: usize,
+    full_sync_interval: Duration,
+    quorum: usize,
+    latest_block: Option<Block>,
+    last_full_sync: Option<Instant>,
+    subgraphs: BTreeMap<SubgraphId, Subgraph>,
+}
+
+/// The entities fetched from the network subgraph by a single sync.
+#[derive(Debug, PartialEq, Eq)]
+enum Changes {
+    /// All the active subgraphs.
+    Full(Vec<SubgraphEntity>),
+    /// The entities changed since the latest block.
+    Incremental {
+        subgraphs: Vec<SubgraphEntity>,
+        deployments: Vec<types::SubgraphDeployment>,
+        indexers: Vec<types::Indexer>,
+    },
 }
 
 impl Client {
+    /// Create a new network subgraph client.
+    ///
+    /// If `quorum` is greater than 1, the fetched entities are only accepted if at least `quorum`
+    /// trusted indexers return the same entities at the same block hash. The quorum is checked
+    /// [`QUORUM_BLOCK_LAG`] blocks behind the head of the primary trusted indexer, and the next
+    /// trusted indexer is tried as the primary if the quorum is not reached.
+    pub fn new(
+        client: IndexerClient,
+        indexers: Vec<TrustedIndexer>,
+        page_size: usize,
+        full_sync_interval: Duration,
+        quorum: usize,
+    ) -> Self {
+        Self {
+            client,
+            indexers,
+            page_size,
+            full_sync_interval,
+            quorum,
+            latest_block: None,
+            last_full_sync: None,
+            subgraphs: Default::default(),
+        }
+    }
+
     /// Fetch the list of subgraphs (and deployments) from the network subgraph.
     pub async fn fetch(&mut self) -> anyhow::Result<Vec<types::Subgraph>> {
         for indexer in &self.indexers.clone() {
-            match self.fetch_from_indexer(indexer).await {
-                Ok(results) => return Ok(results),
+            let full_sync = self
+                .last_full_sync
+                .map(|t| t.elapsed() >= self.full_sync_interval)
+                .unwrap_or(true);
+
+            let at_number = if self.quorum > 1 {
+                match self.fetch_quorum_block_number(indexer).await {
+                    Ok(number) => Some(number),
+                    Err(network_subgraph_query_err) => {
+                        tracing::error!(
+                            indexer = %indexer.url,
+                            network_subgraph_query_err = format!("{network_subgraph_query_err:#}",
+                        ));
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+
+            let mut query_block: Option<Block> = None;
+            let changes = match self
+                .fetch_changes(indexer, full_sync, at_number, &mut query_block)
+                .await
+            {
+                Ok(changes) => changes,
                 Err(network_subgraph_query_err) => {
                     tracing::error!(
                         indexer = %indexer.url,
+                        full_sync,
                         network_subgraph_query_err = format!("{network_subgraph_query_err:#}",
                     ));
+                    // Fall back to a full sync on the next attempt
+                    self.last_full_sync = None;
+                    continue;
                 }
             };
+            let query_block = query_block.unwrap();
+
+            if self.quorum > 1 {
+                if let Err(quorum_err) = self
+                    .check_quorum(indexer, full_sync, &query_block, &changes)
+                    .await
+                {
+                    METRICS.network_subgraph_quorum_err.inc();
+                    tracing::warn!(
+                        indexer = %indexer.url,
+                        quorum_err = format!("{quorum_err:#}"),
+                    );
+                    continue;
+                }
+            }
+
+            match changes {
+                Changes::Full(subgraphs) => {
+                    tracing::debug!(subgraphs = subgraphs.len(), "network subgraph full sync");
+                    self.subgraphs = subgraphs
+                        .into_iter()
+                        .map(|entity| (entity.subgraph.id, entity.subgraph))
+                        .collect();
+                    self.last_full_sync = Some(Instant::now());
+                }
+                Changes::Incremental {
+                    subgraphs,
+                    deployments,
+                    indexers,
+                } => {
+                    tracing::debug!(
+                        subgraphs = subgraphs.len(),
+                        deployments = deployments.len(),
+                        indexers = indexers.len(),
+                        "network subgraph incremental sync"
+                    );
+                    apply_changes(&mut self.subgraphs, subgraphs, deployments, indexers);
+                }
+            };
+            self.latest_block = Some(query_block);
+
+            return Ok(self.subgraphs.values().cloned().collect());
         }
         bail!("trusted indexers exhausted");
     }
 
-    async fn fetch_from_indexer(
-        &mut self,
+    /// Fetch the head block of the trusted indexer, and return the block number at which the quorum
+    /// is checked.
+    async fn fetch_quorum_block_number(
+        &self,
         indexer: &TrustedIndexer,
-    ) -> anyhow::Result<Vec<types::Subgraph>> {
-        // ref: 9936786a-e286-45f3-9190-8409d8389e88
-        let query = r#"
-            query ($block: Block_height!, $first: Int!, $last: String!) {
-                meta: _meta(block: $block) { block { number hash timestamp } }
-                results: subgraphs(
-                    block: $block
-                    orderBy: id, orderDirection: asc
-                    first: $first
-                    where: {
-                        id_gt: $last
-                        entityVersion: 2
-                        versionCount_gte: 1
-                        active: true
-                    }
-                ) {
-                    id
-                    versions(orderBy: version, orderDirection: desc) {
-                        version
-                        subgraphDeployment {
-                            ipfsHash
-                            manifest {
-                                network
-                                startBlock
-                            }
-                            indexerAllocations(
-                                first: 100
-                                orderBy: allocatedTokens, orderDirection: desc
-                                where: { status: Active }
-                            ) {
-                                id
-                                allocatedTokens
-                                indexer {
-                                    id
-                                    url
-                                    stakedTokens
-                                }
-                            }
-                        }
-                    }
-                }
-            }"#;
-
+    ) -> anyhow::Result<BlockNumber> {
         #[derive(Debug, Deserialize)]
         pub struct QueryResponse {
             data: Option<QueryData>,
@@ -199,37 +426,212 @@ impl Client {
         #[derive(Debug, Deserialize)]
         pub struct QueryData {
             meta: Meta,
-            results: Vec<Subgraph>,
         }
+
+        let query = json!({ "query": HEAD_QUERY });
+        let response = self
+            .client
+            .query_indexer(
+                indexer.url.clone(),
+                IndexerAuth::Free(&indexer.auth),
+                &query.to_string(),
+            )
+            .await?;
+        let response: QueryResponse =
+            serde_json::from_str(&response.client_response).context("parse body")?;
+        if !response.errors.is_empty() {
+            bail!("{:?}", response.errors);
+        }
+        let data = response
+            .data
+            .ok_or_else(|| anyhow!("response missing data"))?;
+        let head = Block::try_from(data.meta.block)?;
+        ensure!(
+            (unix_timestamp() / 1_000).saturating_sub(head.timestamp) < 120,
+            "response too far behind",
+        );
+        let latest_block_number = self.latest_block.as_ref().map(|b| b.number).unwrap_or(0);
+        Ok(quorum_block_number(head.number, latest_block_number))
+    }
+
+    /// Check that the other trusted indexers agree with the entities returned by the `primary`
+    /// trusted indexer, at the same block hash.
+    ///
+    /// Trusted indexers failing to respond do not count towards the quorum. Diverging responses
+    /// are logged and reported in the metrics.
+    async fn check_quorum(
+        &self,
+        primary: &TrustedIndexer,
+        full_sync: bool,
+        block: &Block,
+        changes: &Changes,
+    ) -> anyhow::Result<()> {
+        let responses = self
+            .indexers
+            .iter()
+            .filter(|indexer| indexer.url != primary.url)
+            .map(|indexer| async move {
+                let mut query_block = Some(block.clone());
+                let result = self
+                    .fetch_changes(indexer, full_sync, None, &mut query_block)
+                    .await;
+                (indexer, result)
+            });
+        let responses = futures::future::join_all(responses).await;
+
+        let mut agreeing = 1;
+        for (indexer, result) in responses {
+            match result {
+                Ok(other) if &other == changes => agreeing += 1,
+                Ok(_) => {
+                    tracing::warn!(
+                        indexer = %indexer.url,
+                        primary = %primary.url,
+                        block = block.number,
+                        block_hash = %block.hash,
+                        "network subgraph divergence"
+                    );
+                    with_metric(
+                        &METRICS.network_subgraph_divergence,
+                        &[indexer.url.as_str()],
+                        |c| c.inc(),
+                    );
+                }
+                Err(network_subgraph_query_err) => {
+                    tracing::warn!(
+                        indexer = %indexer.url,
+                        network_subgraph_query_err = format!("{network_subgraph_query_err:#}"),
+                    );
+                }
+            };
+        }
+
+        ensure!(
+            agreeing >= self.quorum,
+            "network subgraph quorum not reached ({agreeing}/{})",
+            self.quorum,
+        );
+        Ok(())
+    }
+
+    /// Fetch the entities from the trusted indexer.
+    ///
+    /// A full sync fetches all the active subgraphs. Otherwise, only the subgraphs, deployments
+    /// and indexers changed since the latest block are fetched.
+    ///
+    /// Allocations are not fetched directly. Opening or closing an allocation updates its
+    /// deployment entity, so the deployment allocations are refreshed instead.
+    ///
+    /// See [`Client::paginated_query`] for `at_number` and `query_block`.
+    async fn fetch_changes(
+        &self,
+        indexer: &TrustedIndexer,
+        full_sync: bool,
+        at_number: Option<BlockNumber>,
+        query_block: &mut Option<Block>,
+    ) -> anyhow::Result<Changes> {
+        if full_sync {
+            let subgraphs = self
+                .paginated_query(
+                    indexer,
+                    SUBGRAPHS_QUERY,
+                    json!({ "entityVersion": 2, "versionCount_gte": 1, "active": true }),
+                    at_number,
+                    query_block,
+                )
+                .await?;
+            return Ok(Changes::Full(subgraphs));
+        }
+
+        let since = self
+            .latest_block
+            .as_ref()
+            .map(|b| b.number)
+            .ok_or_else(|| anyhow!("missing latest block"))?;
+        let change_block = json!({ "number_gte": since });
+
+        let subgraphs = self
+            .paginated_query(
+                indexer,
+                SUBGRAPHS_QUERY,
+                json!({ "entityVersion": 2, "_change_block": change_block }),
+                at_number,
+                query_block,
+            )
+            .await?;
+        let deployments = self
+            .paginated_query(
+                indexer,
+                DEPLOYMENTS_QUERY,
+                json!({ "_change_block": change_block }),
+                at_number,
+                query_block,
+            )
+            .await?;
+        let indexers = self
+            .paginated_query(
+                indexer,
+                INDEXERS_QUERY,
+                json!({ "_change_block": change_block }),
+                at_number,
+                query_block,
+            )
+            .await?;
+
+        Ok(Changes::Incremental {
+            subgraphs,
+            deployments,
+            indexers,
+        })
+    }
+
+    /// Send a paginated query to the trusted indexer, returning the entities of all pages.
+    ///
+    /// The query is expected to take `$block`, `$first` and `$where` variables. All pages are
+    /// queried at the same block. If `query_block` is set, it is used as the query block.
+    /// Otherwise, it is set to the block of the first page response, queried at `at_number` if
+    /// given, or at the latest block.
+    async fn paginated_query<T: DeserializeOwned>(
+        &self,
+        indexer: &TrustedIndexer,
+        query: &str,
+        filter: serde_json::Value,
+        at_number: Option<BlockNumber>,
+        query_block: &mut Option<Block>,
+    ) -> anyhow::Result<Vec<T>> {
         #[derive(Debug, Deserialize)]
-        pub struct Meta {
-            block: PartialBlock,
+        #[serde(bound = "T: DeserializeOwned")]
+        pub struct QueryResponse<T> {
+            data: Option<QueryData<T>>,
+            #[serde(default)]
+            errors: Vec<GqlError>,
         }
         #[derive(Debug, Deserialize)]
-        pub struct PartialBlock {
-            pub number: BlockNumber,
-            pub hash: BlockHash,
-            pub timestamp: Option<BlockTimestamp>,
+        #[serde(bound = "T: DeserializeOwned")]
+        pub struct QueryData<T> {
+            meta: Meta,
+            results: Vec<Paginated<T>>,
         }
 
         debug_assert!(self.page_size > 0);
-        let mut query_block: Option<Block> = None;
+        let latest_block_number = self.latest_block.as_ref().map(|b| b.number).unwrap_or(0);
         let mut last_id: Option<String> = None;
-        let mut results: Vec<Subgraph> = Default::default();
+        let mut results: Vec<T> = Default::default();
 
         loop {
-            let block_height = match &query_block {
-                Some(block) => BlockHeight::Hash(block.hash),
-                None => BlockHeight::NumberGte(
-                    self.latest_block.as_ref().map(|b| b.number).unwrap_or(0),
-                ),
+            let block_height = match (&query_block, at_number) {
+                (Some(block), _) => BlockHeight::Hash(block.hash),
+                (None, Some(number)) => BlockHeight::Number(number),
+                (None, None) => BlockHeight::NumberGte(latest_block_number),
             };
+            let mut page_filter = filter.clone();
+            page_filter["id_gt"] = json!(last_id.unwrap_or_default());
             let page_query = json!({
                 "query": query,
                 "variables": {
                     "block": block_height,
                     "first": self.page_size,
-                    "last": last_id.unwrap_or_default(),
+                    "where": page_filter,
                 },
             });
             let response = self
@@ -245,47 +647,184 @@ impl Client {
                 response.client_response,
                 ?response.errors,
             );
-            let response: QueryResponse =
+            let response: QueryResponse<T> =
                 serde_json::from_str(&response.client_response).context("parse body")?;
             if !response.errors.is_empty() {
                 bail!("{:?}", response.errors);
             }
-            let mut data = response
+            let data = response
                 .data
                 .ok_or_else(|| anyhow!("response missing data"))?;
-            let block = Block {
-                number: data.meta.block.number,
-                hash: data.meta.block.hash,
-                timestamp: data
-                    .meta
-                    .block
-                    .timestamp
-                    .ok_or_else(|| anyhow!("response missing block timestamp"))?,
-            };
+            let block = Block::try_from(data.meta.block)?;
             if let Some(query_block) = &query_block {
                 ensure!(query_block == &block);
+            } else if let Some(number) = at_number {
+                ensure!(block.number == number, "response block mismatch");
+                *query_block = Some(block);
             } else {
                 ensure!(
-                    data.meta.block.number
-                        >= self.latest_block.as_ref().map(|b| b.number).unwrap_or(0),
+                    block.number >= latest_block_number,
                     "response block before latest",
                 );
                 ensure!(
                     (unix_timestamp() / 1_000).saturating_sub(block.timestamp) < 120,
                     "response too far behind",
                 );
-                query_block = Some(block);
+                *query_block = Some(block);
             }
-            last_id = data.results.last().map(|entry| entry.id.to_string());
+            last_id = data.results.last().map(|entry| entry.entity_id.clone());
             let page_len = data.results.len();
-            results.append(&mut data.results);
+            results.extend(data.results.into_iter().map(|entry| entry.entity));
             if page_len < self.page_size {
                 break;
             }
         }
 
-        self.latest_block = Some(query_block.unwrap());
-
         Ok(results)
     }
 }
+
+/// Returns the block number at which the quorum is checked, given the head of the primary trusted
+/// indexer. This is never before the latest block, since the changes are fetched since then.
+fn quorum_block_number(head: BlockNumber, latest: BlockNumber) -> BlockNumber {
+    head.saturating_sub(QUORUM_BLOCK_LAG).max(latest)
+}
+
+/// Apply the changed entities to the subgraphs fetched so far.
+///
+/// - Changed subgraphs replace the previous ones. Inactive subgraphs, or subgraphs without
+///   versions, are removed.
+/// - Changed deployments replace the previous ones in all the subgraph versions.
+/// - Changed indexers replace the previous ones in all the allocations.
+fn apply_changes(
+    cache: &mut BTreeMap<SubgraphId, Subgraph>,
+    subgraphs: Vec<SubgraphEntity>,
+    deployments: Vec<types::SubgraphDeployment>,
+    indexers: Vec<types::Indexer>,
+) {
+    for SubgraphEntity { active, subgraph } in subgraphs {
+        if active && !subgraph.versions.is_empty() {
+            cache.insert(subgraph.id, subgraph);
+        } else {
+            cache.remove(&subgraph.id);
+        }
+    }
+
+    let deployments: HashMap<DeploymentId, types::SubgraphDeployment> = deployments
+        .into_iter()
+        .map(|deployment| (deployment.id, deployment))
+        .collect();
+    let indexers: HashMap<IndexerId, types::Indexer> = indexers
+        .into_iter()
+        .map(|indexer| (indexer.id, indexer))
+        .collect();
+
+    for version in cache.values_mut().flat_map(|s| s.versions.iter_mut()) {
+        if let Some(deployment) = deployments.get(&version.subgraph_deployment.id) {
+            version.subgraph_deployment = deployment.clone();
+        }
+        for allocation in &mut version.subgraph_deployment.allocations {
+            if let Some(indexer) = indexers.get(&allocation.indexer.id) {
+                allocation.indexer = indexer.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use thegraph_core::{alloy::primitives::Address, AllocationId, DeploymentId, SubgraphId};
+
+    use super::{
+        apply_changes, quorum_block_number,
+        types::{Allocation, Indexer, Subgraph, SubgraphDeployment, SubgraphVersion},
+        SubgraphEntity,
+    };
+
+    fn indexer(n: u8, url: &str) -> Indexer {
+        Indexer {
+            id: Address::repeat_byte(n).into(),
+            url: Some(url.to_string()),
+            staked_tokens: 0,
+        }
+    }
+
+    fn deployment(n: u8, indexers: &[Indexer]) -> SubgraphDeployment {
+        SubgraphDeployment {
+            id: DeploymentId::new([n; 32].into()),
+            manifest: None,
+            allocations: indexers
+                .iter()
+                .map(|indexer| Allocation {
+                    id: AllocationId::from(Address::repeat_byte(n)),
+                    allocated_tokens: 1,
+                    indexer: indexer.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn subgraph(n: u8, deployment: SubgraphDeployment) -> Subgraph {
+        Subgraph {
+            id: SubgraphId::new([n; 32].into()),
+            versions: vec![SubgraphVersion {
+                version: 0,
+                subgraph_deployment: deployment,
+            }],
+        }
+    }
+
+    #[test]
+    fn apply_incremental_changes() {
+        //* Given
+        let indexer_a = indexer(1, "https://a.example.com/");
+        let mut cache: BTreeMap<SubgraphId, Subgraph> = [
+            subgraph(1, deployment(1, &[indexer_a.clone()])),
+            subgraph(2, deployment(2, &[indexer_a.clone()])),
+        ]
+        .into_iter()
+        .map(|s| (s.id, s))
+        .collect();
+
+        //* When
+        let indexer_a = indexer(1, "https://new-a.example.com/");
+        let indexer_b = indexer(2, "https://b.example.com/");
+        apply_changes(
+            &mut cache,
+            vec![
+                SubgraphEntity {
+                    active: false,
+                    subgraph: subgraph(2, deployment(2, &[])),
+                },
+                SubgraphEntity {
+                    active: true,
+                    subgraph: subgraph(3, deployment(3, &[indexer_b.clone()])),
+                },
+            ],
+            vec![deployment(1, &[indexer_a.clone(), indexer_b.clone()])],
+            vec![indexer_a],
+        );
+
+        //* Then
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&SubgraphId::new([2; 32].into())));
+
+        let subgraph_1 = &cache[&SubgraphId::new([1; 32].into())];
+        let allocations = &subgraph_1.versions[0].subgraph_deployment.allocations;
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(
+            allocations[0].indexer.url.as_deref(),
+            Some("https://new-a.example.com/")
+        );
+        assert!(cache.contains_key(&SubgraphId::new([3; 32].into())));
+    }
+
+    #[test]
+    fn quorum_block_behind_head() {
+        assert_eq!(quorum_block_number(100, 50), 90);
+        assert_eq!(quorum_block_number(100, 95), 95);
+        assert_eq!(quorum_block_number(5, 0), 0);
+    }
+}
@@ -0,0 +1,359 @@
+//! Network topology change events.
+//!
+//! After each network topology update, the new snapshot is compared to the previous one, and the
+//! differences are published as [`TopologyEvent`]s. This makes changes like an indexer appearing
+//! or an indexing becoming unavailable visible, without having to diff the logs.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use thegraph_core::{AllocationId, DeploymentId, IndexerId, SubgraphId};
+use tokio::sync::{broadcast, mpsc};
+
+use super::{
+    errors::IndexingError,
+    internal::{Indexing, IndexingId, NetworkTopologySnapshot},
+};
+
+/// A change between two consecutive network topology snapshots.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TopologyEvent {
+    /// The indexer has an indexing in the new snapshot, but none in the previous one.
+    IndexerAdded { indexer: IndexerId },
+    /// The indexer has no indexing in the new snapshot.
+    IndexerRemoved { indexer: IndexerId },
+    /// The indexer URL changed.
+    IndexerUrlChanged {
+        indexer: IndexerId,
+        old_url: String,
+        new_url: String,
+    },
+    /// The indexing is available to serve queries.
+    IndexingAvailable {
+        indexer: IndexerId,
+        deployment: DeploymentId,
+    },
+    /// The indexing is not available to serve queries, e.g. the indexer is blocked or below the
+    /// minimum version.
+    IndexingUnavailable {
+        indexer: IndexerId,
+        deployment: DeploymentId,
+        reason: String,
+    },
+    /// The indexer no longer has an allocation on the deployment.
+    IndexingRemoved {
+        indexer: IndexerId,
+        deployment: DeploymentId,
+    },
+    /// The largest allocation of the indexing changed.
+    AllocationChanged {
+        indexer: IndexerId,
+        deployment: DeploymentId,
+        old_allocation: AllocationId,
+        new_allocation: AllocationId,
+    },
+    /// A new version of the subgraph was published.
+    SubgraphVersionPublished {
+        subgraph: SubgraphId,
+        deployment: DeploymentId,
+    },
+}
+
+/// Spawn a task forwarding the published events to the reporter, until the events channel is
+/// closed.
+pub fn forward(
+    mut events: broadcast::Receiver<TopologyEvent>,
+    reporter: mpsc::UnboundedSender<TopologyEvent>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "topology events dropped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if reporter.send(event).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Returns the changes from the `old` to the `new` network topology snapshot.
+pub fn diff(old: &NetworkTopologySnapshot, new: &NetworkTopologySnapshot) -> Vec<TopologyEvent> {
+    let mut events = vec![];
+
+    let old_indexings = indexings(old);
+    let new_indexings = indexings(new);
+
+    // Indexers
+    let old_indexers = indexers(&old_indexings);
+    let new_indexers = indexers(&new_indexings);
+    for (indexer, new_url) in &new_indexers {
+        match old_indexers.get(indexer) {
+            None => events.push(TopologyEvent::IndexerAdded { indexer: *indexer }),
+            Some(old_url) => {
+                if let (Some(old_url), Some(new_url)) = (old_url, new_url) {
+                    if old_url != new_url {
+                        events.push(TopologyEvent::IndexerUrlChanged {
+                            indexer: *indexer,
+                            old_url: old_url.clone(),
+                            new_url: new_url.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    for indexer in old_indexers.keys() {
+        if !new_indexers.contains_key(indexer) {
+            events.push(TopologyEvent::IndexerRemoved { indexer: *indexer });
+        }
+    }
+
+    // Indexings
+    for (id, new_indexing) in &new_indexings {
+        let old_indexing = old_indexings.get(id);
+        match (old_indexing, new_indexing) {
+            (Some(Ok(old_indexing)), Ok(new_indexing)) => {
+                if old_indexing.largest_allocation != new_indexing.largest_allocation {
+                    events.push(TopologyEvent::AllocationChanged {
+                        indexer: id.indexer,
+                        deployment: id.deployment,
+                        old_allocation: old_indexing.largest_allocation,
+                        new_allocation: new_indexing.largest_allocation,
+                    });
+                }
+            }
+            (Some(Err(_)) | None, Ok(_)) => events.push(TopologyEvent::IndexingAvailable {
+                indexer: id.indexer,
+                deployment: id.deployment,
+            }),
+            (_, Err(new_err)) => {
+                let reason = new_err.to_string();
+                if !matches!(old_indexing, Some(Err(old_err)) if old_err.to_string() == reason) {
+                    events.push(TopologyEvent::IndexingUnavailable {
+                        indexer: id.indexer,
+                        deployment: id.deployment,
+                        reason,
+                    });
+                }
+            }
+        }
+    }
+    for id in old_indexings.keys() {
+        if !new_indexings.contains_key(id) {
+            events.push(TopologyEvent::IndexingRemoved {
+                indexer: id.indexer,
+                deployment: id.deployment,
+            });
+        }
+    }
+
+    // Subgraph versions
+    for (subgraph, new_subgraph) in &new.subgraphs {
+        let new_subgraph = match new_subgraph {
+            Ok(subgraph) => subgraph,
+            Err(_) => continue,
+        };
+        let old_versions: &[DeploymentId] = match old.subgraphs.get(subgraph) {
+            Some(Ok(old_subgraph)) => &old_subgraph.versions,
+            _ => &[],
+        };
+        for deployment in &new_subgraph.versions {
+            if !old_versions.contains(deployment) {
+                events.push(TopologyEvent::SubgraphVersionPublished {
+                    subgraph: *subgraph,
+                    deployment: *deployment,
+                });
+            }
+        }
+    }
+
+    events
+}
+
+/// Collect the indexings of all the snapshot's deployments.
+fn indexings(
+    snapshot: &NetworkTopologySnapshot,
+) -> BTreeMap<IndexingId, &Result<Indexing, IndexingError>> {
+    snapshot
+        .deployments
+        .values()
+        .filter_map(|deployment| deployment.as_ref().ok())
+        .flat_map(|deployment| deployment.indexings.iter())
+        .map(|(id, indexing)| (*id, indexing))
+        .collect()
+}
+
+/// Collect the indexers of the given indexings, along with their URL. The URL is only known for
+/// indexers with at least one available indexing.
+fn indexers(
+    indexings: &BTreeMap<IndexingId, &Result<Indexing, IndexingError>>,
+) -> BTreeMap<IndexerId, Option<String>> {
+    let mut indexers: BTreeMap<IndexerId, Option<String>> = BTreeMap::new();
+    for (id, indexing) in indexings {
+        let url = indexers.entry(id.indexer).or_default();
+        if let Ok(indexing) = indexing {
+            url.get_or_insert_with(|| indexing.indexer.url.to_string());
+        }
+    }
+    indexers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use thegraph_core::{
+        alloy::primitives::Address, AllocationId, DeploymentId, IndexerId, SubgraphId,
+    };
+
+    use super::{diff, TopologyEvent};
+    use crate::network::{
+        errors::IndexingError,
+        internal::{
+            Deployment, Indexer, Indexing, IndexingAllocation, IndexingId, IndexingProgress,
+            NetworkTopologySnapshot, Subgraph,
+        },
+    };
+
+    fn indexing(id: IndexingId, url: &str, allocation: u8) -> Indexing {
+        Indexing {
+            id,
+            largest_allocation: AllocationId::from(Address::repeat_byte(allocation)),
+            allocations: vec![IndexingAllocation {
+                id: AllocationId::from(Address::repeat_byte(allocation)),
+                allocated_tokens: 1,
+            }],
+            total_allocated_tokens: 1,
+            indexer: Arc::new(Indexer {
+                id: id.indexer,
+                url: url.parse().unwrap(),
+                indexer_service_version: "1.0.0".parse().unwrap(),
+                graph_node_version: "0.35.0".parse().unwrap(),
+                tap_support: true,
+                staked_tokens: 1,
+            }),
+            progress: IndexingProgress {
+                latest_block: 1,
+                min_block: None,
+            },
+            cost_model: Default::default(),
+        }
+    }
+
+    fn snapshot(
+        versions: Vec<DeploymentId>,
+        indexings: Vec<Result<Indexing, IndexingId>>,
+    ) -> NetworkTopologySnapshot {
+        let subgraph_id = SubgraphId::new([1; 32].into());
+        let indexings: HashMap<IndexingId, Result<Indexing, IndexingError>> = indexings
+            .into_iter()
+            .map(|indexing| match indexing {
+                Ok(indexing) => (indexing.id, Ok(indexing)),
+                Err(id) => (id, Err(IndexingError::Internal("test"))),
+            })
+            .collect();
+        let deployments = versions
+            .iter()
+            .map(|deployment| {
+                let deployment_indexings = indexings
+                    .iter()
+                    .filter(|(id, _)| &id.deployment == deployment)
+                    .map(|(id, indexing)| (*id, indexing.clone()))
+                    .collect();
+                let deployment_info = Deployment {
+                    chain: "mainnet".to_string(),
+                    start_block: 0,
+                    subgraphs: HashSet::from([subgraph_id]),
+                    indexings: deployment_indexings,
+                };
+                (*deployment, Ok(deployment_info))
+            })
+            .collect();
+        let subgraph = Subgraph {
+            id: subgraph_id,
+            chain: "mainnet".to_string(),
+            start_block: 0,
+            versions,
+            indexings,
+        };
+        NetworkTopologySnapshot {
+            subgraphs: HashMap::from([(subgraph_id, Ok(subgraph))]),
+            deployments,
+        }
+    }
+
+    #[test]
+    fn diff_topology_snapshots() {
+        //* Given
+        let deployment_1 = DeploymentId::new([1; 32].into());
+        let deployment_2 = DeploymentId::new([2; 32].into());
+        let indexer_1 = IndexerId::from(Address::repeat_byte(1));
+        let indexer_2 = IndexerId::from(Address::repeat_byte(2));
+        let indexing_1 = IndexingId {
+            indexer: indexer_1,
+            deployment: deployment_1,
+        };
+        let indexing_2 = IndexingId {
+            indexer: indexer_2,
+            deployment: deployment_1,
+        };
+        let indexing_3 = IndexingId {
+            indexer: indexer_1,
+            deployment: deployment_2,
+        };
+
+        let old = snapshot(
+            vec![deployment_1],
+            vec![
+                Ok(indexing(indexing_1, "http://indexer-1.example.com", 1)),
+                Ok(indexing(indexing_2, "http://indexer-2.example.com", 2)),
+            ],
+        );
+        let new = snapshot(
+            vec![deployment_2, deployment_1],
+            vec![
+                Ok(indexing(indexing_1, "http://indexer-1.example.com", 3)),
+                Err(indexing_2),
+                Ok(indexing(indexing_3, "http://indexer-1.example.com", 4)),
+            ],
+        );
+
+        //* When
+        let events = diff(&old, &new);
+
+        //* Then
+        assert_eq!(events.len(), 4);
+        assert!(events.contains(&TopologyEvent::AllocationChanged {
+            indexer: indexer_1,
+            deployment: deployment_1,
+            old_allocation: AllocationId::from(Address::repeat_byte(1)),
+            new_allocation: AllocationId::from(Address::repeat_byte(3)),
+        }));
+        assert!(events.contains(&TopologyEvent::IndexingUnavailable {
+            indexer: indexer_2,
+            deployment: deployment_1,
+            reason: "internal error: test".to_string(),
+        }));
+        assert!(events.contains(&TopologyEvent::IndexingAvailable {
+            indexer: indexer_1,
+            deployment: deployment_2,
+        }));
+        assert!(events.contains(&TopologyEvent::SubgraphVersionPublished {
+            subgraph: SubgraphId::new([1; 32].into()),
+            deployment: deployment_2,
+        }));
+
+        // No changes between identical snapshots
+        assert!(diff(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+//! Query features not supported by all graph-node versions.
+//!
+//! Queries using these features fail on indexers running older graph-node versions. The features
+//! used by a query are detected before selecting the candidates, so that indexers unable to serve
+//! the query are filtered out before any receipt is issued.
+
+use std::collections::BTreeSet;
+
+use cost_model::{Context, QueryVariables};
+use graphql::graphql_parser::query::{OperationDefinition, Selection, SelectionSet, Text, Value};
+use semver::Version;
+
+/// A query feature requiring a minimum graph-node version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QueryFeature {
+    /// Ordering by child entity fields, e.g. `orderBy: block__timestamp`.
+    ChildOrderBy,
+    /// `and`/`or` filters in `where` arguments.
+    AndOrFilters,
+}
+
+/// The feature table, mapping the query features to the minimum graph-node version supporting
+/// them.
+static FEATURE_TABLE: [(QueryFeature, Version); 2] = [
+    (QueryFeature::ChildOrderBy, Version::new(0, 28, 0)),
+    (QueryFeature::AndOrFilters, Version::new(0, 30, 0)),
+];
+
+impl QueryFeature {
+    /// The minimum graph-node version supporting the feature.
+    pub fn min_graph_node_version(&self) -> &'static Version {
+        FEATURE_TABLE
+            .iter()
+            .find_map(|(feature, version)| (feature == self).then_some(version))
+            .expect("feature missing from the feature table")
+    }
+}
+
+/// Returns the minimum graph-node version required to serve the query, if the query uses any of
+/// the features in the feature table.
+pub fn required_graph_node_version(ctx: &Context<'_>) -> Option<&'static Version> {
+    detect_features(ctx)
+        .iter()
+        .map(QueryFeature::min_graph_node_version)
+        .max()
+}
+
+/// Detect the query features used by the query.
+pub fn detect_features(ctx: &Context<'_>) -> BTreeSet<QueryFeature> {
+    let mut features = BTreeSet::new();
+    for operation in &ctx.operations {
+        match operation {
+            OperationDefinition::SelectionSet(selection_set) => {
+                visit_selection_set(selection_set, &ctx.variables, &mut features);
+            }
+            OperationDefinition::Query(query) => {
+                visit_selection_set(&query.selection_set, &ctx.variables, &mut features);
+            }
+            OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) => (),
+        }
+    }
+    for fragment in &ctx.fragments {
+        visit_selection_set(&fragment.selection_set, &ctx.variables, &mut features);
+    }
+    features
+}
+
+fn visit_selection_set<'q, T: Text<'q>>(
+    selection_set: &SelectionSet<'q, T>,
+    vars: &QueryVariables,
+    features: &mut BTreeSet<QueryFeature>,
+) {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                for (name, value) in &field.arguments {
+                    match name.as_ref() {
+                        "orderBy" if is_child_order_by(value, vars) => {
+                            features.insert(QueryFeature::ChildOrderBy);
+                        }
+                        "where" if has_and_or_filter(value, vars) => {
+                            features.insert(QueryFeature::AndOrFilters);
+                        }
+                        _ => (),
+                    };
+                }
+                visit_selection_set(&field.selection_set, vars, features);
+            }
+            Selection::InlineFragment(fragment) => {
+                visit_selection_set(&fragment.selection_set, vars, features);
+            }
+            // Fragment definitions are visited separately
+            Selection::FragmentSpread(_) => (),
+        }
+    }
+}
+
+fn is_child_order_by<'c, T: Text<'c>>(value: &Value<'c, T>, vars: &QueryVariables) -> bool {
+    match value {
+        Value::Enum(name) => name.as_ref().contains("__"),
+        Value::String(name) => name.contains("__"),
+        Value::Variable(name) => vars
+            .get(name.as_ref())
+            .map(|value| is_child_order_by(value, vars))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn has_and_or_filter<'c, T: Text<'c>>(value: &Value<'c, T>, vars: &QueryVariables) -> bool {
+    match value {
+        Value::Object(fields) => fields.iter().any(|(name, value)| {
+            matches!(name.as_ref(), "and" | "or") || has_and_or_filter(value, vars)
+        }),
+        Value::List(values) => values.iter().any(|value| has_and_or_filter(value, vars)),
+        Value::Variable(name) => vars
+            .get(name.as_ref())
+            .map(|value| has_and_or_filter(value, vars))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use cost_model::Context;
+    use semver::Version;
+
+    use super::{detect_features, required_graph_node_version, QueryFeature};
+
+    #[test]
+    fn detect_query_features() {
+        let tests = [
+            ("{ a { id } }", "", vec![]),
+            (
+                "{ a(orderBy: block__timestamp) { id } }",
+                "",
+                vec![QueryFeature::ChildOrderBy],
+            ),
+            (
+                "{ a(where: { or: [{ b: 1 }, { c: 2 }] }) { id } }",
+                "",
+                vec![QueryFeature::AndOrFilters],
+            ),
+            (
+                "query q($w: A_filter) { a { b(where: $w) { id } } }",
+                r#"{ "w": { "and": [{ "b": 1 }] } }"#,
+                vec![QueryFeature::AndOrFilters],
+            ),
+            (
+                "{ ...f } fragment f on Query { a(orderBy: \"b__c\") { id } }",
+                "",
+                vec![QueryFeature::ChildOrderBy],
+            ),
+        ];
+        for (query, variables, expected) in tests {
+            let ctx = Context::new(query, variables).expect("invalid query");
+            let expected: BTreeSet<QueryFeature> = expected.into_iter().collect();
+            assert_eq!(detect_features(&ctx), expected, "{query}");
+        }
+    }
+
+    #[test]
+    fn required_version_is_the_max_of_the_features() {
+        let ctx = Context::new("{ a(orderBy: b__c, where: { or: [{ b: 1 }] }) { id } }", "")
+            .expect("invalid query");
+        assert_eq!(
+            required_graph_node_version(&ctx),
+            Some(&Version::new(0, 30, 0))
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! Per-API key query policy.
+//!
+//! API keys may restrict the queries they are used for: introspection, query depth, number of root
+//! fields, and response size. The query restrictions are checked on the parsed query, before
+//! selecting the candidates, and the response size on the indexer response, before it is returned
+//! to the client.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, ensure};
+use cost_model::Context;
+use graphql::graphql_parser::query::{
+    FragmentDefinition, OperationDefinition, Selection, SelectionSet, Text,
+};
+use serde::Deserialize;
+
+/// Root fields of the introspection queries.
+const INTROSPECTION_FIELDS: [&str; 2] = ["__schema", "__type"];
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct QueryPolicy {
+    /// Allow introspection queries (`__schema` and `__type` root fields). Defaults to true.
+    pub allow_introspection: bool,
+    /// Maximum depth of the query selection sets
+    pub max_depth: Option<usize>,
+    /// Maximum number of root fields in the query
+    pub max_root_fields: Option<usize>,
+    /// Maximum size, in bytes, of the query response
+    pub max_response_bytes: Option<usize>,
+}
+
+impl Default for QueryPolicy {
+    fn default() -> Self {
+        Self {
+            allow_introspection: true,
+            max_depth: None,
+            max_root_fields: None,
+            max_response_bytes: None,
+        }
+    }
+}
+
+impl QueryPolicy {
+    /// Returns true if the policy restricts the queries themselves, and not only their responses.
+    pub fn restricts_query(&self) -> bool {
+        !self.allow_introspection || self.max_depth.is_some() || self.max_root_fields.is_some()
+    }
+
+    /// Check that the query is allowed by the policy.
+    pub fn check_query(&self, ctx: &Context<'_>) -> anyhow::Result<()> {
+        if !self.restricts_query() {
+            return Ok(());
+        }
+
+        let selection_sets = query_selection_sets(ctx);
+        let root_fields = root_fields(&selection_sets, &ctx.fragments);
+
+        if !self.allow_introspection && has_introspection_field(&root_fields) {
+            bail!("introspection queries are not allowed for this API key");
+        }
+        if let Some(max_root_fields) = self.max_root_fields {
+            ensure!(
+                root_fields.len() <= max_root_fields,
+                "query has {} root fields, exceeding the maximum of {max_root_fields}",
+                root_fields.len(),
+            );
+        }
+        if let Some(max_depth) = self.max_depth {
+            let mut fragment_depths = HashMap::new();
+            let depth = selection_sets
+                .iter()
+                .map(|selection_set| {
+                    depth(
+                        selection_set,
+                        &ctx.fragments,
+                        max_depth,
+                        &mut fragment_depths,
+                    )
+                })
+                .max()
+                .unwrap_or(0);
+            ensure!(
+                depth <= max_depth,
+                "query depth exceeds the maximum of {max_depth} for this API key"
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the query response is allowed by the policy.
+    pub fn check_response(&self, response: &str) -> anyhow::Result<()> {
+        if let Some(max_response_bytes) = self.max_response_bytes {
+            ensure!(
+                response.len() <= max_response_bytes,
+                "response size exceeds the maximum of {max_response_bytes} bytes for this API key"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn has_introspection_field(root_fields: &[String]) -> bool {
+    root_fields
+        .iter()
+        .any(|field| INTROSPECTION_FIELDS.contains(&field.as_str()))
+}
+
+/// Returns the selection sets of the query operations.
+fn query_selection_sets<'c, 'q>(ctx: &'c Context<'q>) -> Vec<&'c SelectionSet<'q, &'q str>> {
+    ctx.operations
+        .iter()
+        .filter_map(|operation| match operation {
+            OperationDefinition::SelectionSet(selection_set) => Some(selection_set),
+            OperationDefinition::Query(query) => Some(&query.selection_set),
+            OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) => None,
+        })
+        .collect()
+}
+
+/// Returns the names of the root fields of the selection sets, expanding the fragments.
+fn root_fields<'q, T: Text<'q>>(
+    selection_sets: &[&SelectionSet<'q, T>],
+    fragments: &[FragmentDefinition<'q, T>],
+) -> Vec<String> {
+    let mut fields: Vec<String> = vec![];
+    let mut expanded = HashSet::new();
+    for selection_set in selection_sets {
+        collect_root_fields(selection_set, fragments, &mut expanded, &mut fields);
+    }
+    fields
+}
+
+/// Collect the names of the fields of the selection set, expanding the fragments. Each fragment is
+/// expanded at most once, since spreading it again selects the same fields. This also stops at
+/// cyclic fragments, which are rejected by the indexers anyway.
+fn collect_root_fields<'f, 'q, T: Text<'q>>(
+    selection_set: &'f SelectionSet<'q, T>,
+    fragments: &'f [FragmentDefinition<'q, T>],
+    expanded: &mut HashSet<&'f str>,
+    fields: &mut Vec<String>,
+) {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => fields.push(field.name.as_ref().to_string()),
+            Selection::InlineFragment(fragment) => {
+                collect_root_fields(&fragment.selection_set, fragments, expanded, fields);
+            }
+            Selection::FragmentSpread(spread) => {
+                let fragment = find_fragment(fragments, spread.fragment_name.as_ref());
+                if let Some(fragment) = fragment {
+                    if expanded.insert(fragment.name.as_ref()) {
+                        collect_root_fields(&fragment.selection_set, fragments, expanded, fields);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the depth of the selection set, capped at `limit + 1`.
+///
+/// The depth of each fragment is memoized by fragment name and remaining limit, so that each
+/// fragment is inspected at most once per nesting level. Cyclic fragment spreads count as depth 0.
+fn depth<'f, 'q, T: Text<'q>>(
+    selection_set: &'f SelectionSet<'q, T>,
+    fragments: &'f [FragmentDefinition<'q, T>],
+    limit: usize,
+    fragment_depths: &mut HashMap<(&'f str, usize), usize>,
+) -> usize {
+    selection_set
+        .items
+        .iter()
+        .map(|selection| match selection {
+            Selection::Field(field) if field.selection_set.items.is_empty() || limit == 0 => 1,
+            Selection::Field(field) => {
+                1 + depth(&field.selection_set, fragments, limit - 1, fragment_depths)
+            }
+            Selection::InlineFragment(fragment) => {
+                depth(&fragment.selection_set, fragments, limit, fragment_depths)
+            }
+            Selection::FragmentSpread(spread) => {
+                let Some(fragment) = find_fragment(fragments, spread.fragment_name.as_ref()) else {
+                    return 0;
+                };
+                let key = (fragment.name.as_ref(), limit);
+                if let Some(fragment_depth) = fragment_depths.get(&key) {
+                    return *fragment_depth;
+                }
+                fragment_depths.insert(key, 0);
+                let fragment_depth =
+                    depth(&fragment.selection_set, fragments, limit, fragment_depths);
+                fragment_depths.insert(key, fragment_depth);
+                fragment_depth
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn find_fragment<'f, 'q, T: Text<'q>>(
+    fragments: &'f [FragmentDefinition<'q, T>],
+    name: &str,
+) -> Option<&'f FragmentDefinition<'q, T>> {
+    fragments
+        .iter()
+        .find(|fragment| fragment.name.as_ref() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use cost_model::Context;
+
+    use super::QueryPolicy;
+
+    #[test]
+    fn check_queries() {
+        let restricted = QueryPolicy {
+            allow_introspection: false,
+            max_depth: Some(3),
+            max_root_fields: Some(2),
+            max_response_bytes: None,
+        };
+        let tests = [
+            ("{ a { id } b { id } }", true),
+            ("{ a { b { c } } }", true),
+            ("{ a { b { c { d } } } }", false),
+            ("{ a { ...f } } fragment f on A { b { c { d } } }", false),
+            ("{ a b c }", false),
+            ("{ ...f } fragment f on Query { a b c }", false),
+            ("{ __schema { types { name } } }", false),
+            ("{ a { __typename } }", true),
+        ];
+        for (query, allowed) in tests {
+            let ctx = Context::new(query, "").expect("invalid query");
+            assert_eq!(restricted.check_query(&ctx).is_ok(), allowed, "{query}");
+            assert!(QueryPolicy::default().check_query(&ctx).is_ok(), "{query}");
+        }
+    }
+
+    #[test]
+    fn expand_fragments_once() {
+        //* Given
+        let restricted = QueryPolicy {
+            allow_introspection: false,
+            max_depth: Some(3),
+            max_root_fields: Some(2),
+            max_response_bytes: None,
+        };
+        let mut query = "{ ...f0 }".to_string();
+        for i in 0..31 {
+            query.push_str(&format!(
+                " fragment f{i} on Query {{ ...f{} ...f{} }}",
+                i + 1,
+                i + 1
+            ));
+        }
+        query.push_str(" fragment f31 on Query { a }");
+        let ctx = Context::new(&query, "").expect("invalid query");
+
+        //* When
+        let result = restricted.check_query(&ctx);
+
+        //* Then
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn check_responses() {
+        let restricted = QueryPolicy {
+            max_response_bytes: Some(16),
+            ..Default::default()
+        };
+        assert!(restricted.check_response(r#"{"data":{}}"#).is_ok());
+        assert!(restricted
+            .check_response(r#"{"data":{"a":"0123456789"}}"#)
+            .is_err());
+        assert!(QueryPolicy::default()
+            .check_response(r#"{"data":{"a":"0123456789"}}"#)
+            .is_ok());
+    }
+}
@@ -1,11 +1,30 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Context};
 use ordered_float::NotNan;
 use prost::Message;
+use serde::Serialize;
 use thegraph_core::{alloy::primitives::Address, AllocationId, DeploymentId, IndexerId};
 use tokio::sync::mpsc;
 
 use crate::{concat_bytes, errors, indexer_client::IndexerResponse, receipts::Receipt};
 
+/// The Kafka producer, shared by all the reporters.
+pub type KafkaProducer = rdkafka::producer::ThreadedProducer<
+    rdkafka::producer::DefaultProducerContext,
+    rdkafka::producer::NoCustomPartitioner,
+>;
+
+pub fn create_kafka_producer(
+    kafka_config: impl Into<rdkafka::ClientConfig>,
+) -> anyhow::Result<Arc<KafkaProducer>> {
+    let kafka_producer = kafka_config
+        .into()
+        .create()
+        .context("kafka producer error")?;
+    Ok(Arc::new(kafka_producer))
+}
+
 pub struct ClientRequest {
     pub id: String,
     pub response_time_ms: u16,
@@ -21,7 +40,7 @@ pub struct ClientRequest {
 pub struct IndexerRequest {
     pub indexer: IndexerId,
     pub deployment: DeploymentId,
-    pub largest_allocation: AllocationId,
+    pub allocation: AllocationId,
     pub url: String,
     pub receipt: Receipt,
     pub subgraph_chain: String,
@@ -37,10 +56,7 @@ pub struct Reporter {
     pub graph_env: String,
     pub topics: Topics,
     pub write_buf: Vec<u8>,
-    pub kafka_producer: rdkafka::producer::ThreadedProducer<
-        rdkafka::producer::DefaultProducerContext,
-        rdkafka::producer::NoCustomPartitioner,
-    >,
+    pub kafka_producer: Arc<KafkaProducer>,
 }
 
 pub struct Topics {
@@ -53,12 +69,8 @@ impl Reporter {
         tap_signer: Address,
         graph_env: String,
         topics: Topics,
-        kafka_config: impl Into<rdkafka::ClientConfig>,
-    ) -> anyhow::Result<mpsc::UnboundedSender<ClientRequest>> {
-        let kafka_producer = kafka_config
-            .into()
-            .create()
-            .context("kafka producer error")?;
+        kafka_producer: Arc<KafkaProducer>,
+    ) -> mpsc::UnboundedSender<ClientRequest> {
         let mut reporter = Self {
             tap_signer,
             graph_env,
@@ -75,7 +87,7 @@ impl Reporter {
                 }
             }
         });
-        Ok(tx)
+        tx
     }
 
     fn report(&mut self, client_request: ClientRequest) -> anyhow::Result<()> {
@@ -185,6 +197,32 @@ impl Reporter {
     }
 }
 
+/// Report the messages sent to the returned channel to the given Kafka topic, as JSON messages of
+/// the form `{ "gateway_id": <graph_env>, <field>: <message> }`.
+pub fn create_json_reporter<T: Serialize + Send + 'static>(
+    kafka_producer: Arc<KafkaProducer>,
+    graph_env: String,
+    topic: &'static str,
+    field: &'static str,
+) -> mpsc::UnboundedSender<T> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<T>();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let payload = serde_json::to_vec(&serde_json::json!({
+                "gateway_id": &graph_env,
+                field: msg,
+            }))
+            .unwrap();
+            let record: rdkafka::producer::BaseRecord<(), [u8], ()> =
+                rdkafka::producer::BaseRecord::to(topic).payload(&payload);
+            if let Err((report_err, _)) = kafka_producer.send(record) {
+                tracing::error!(%report_err, topic);
+            }
+        }
+    });
+    tx
+}
+
 #[derive(prost::Message)]
 pub struct ClientQueryProtobuf {
     #[prost(string, tag = "1")]
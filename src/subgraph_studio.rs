@@ -1,5 +1,12 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
+use anyhow::Context as _;
+use reqwest::{header, StatusCode};
 use serde::Deserialize;
 use tokio::{
     sync::watch,
@@ -7,89 +14,259 @@ use tokio::{
 };
 use url::Url;
 
-use crate::auth::{APIKey, QueryStatus};
+use crate::{
+    auth::{APIKey, QueryStatus},
+    fs,
+    metrics::METRICS,
+};
 
+/// Fetch the API keys from the Studio endpoint every 30 seconds, replacing the API keys map.
+///
+/// The API keys are only downloaded when they changed since the last fetch, using the `ETag` of
+/// the last response. When they didn't change, the last fetched API keys are sent again, to
+/// reconcile the updates pushed in between. If a cache file is given, the last fetched API keys
+/// are persisted to it, and loaded from it when the endpoint is unavailable before the first
+/// successful fetch.
+///
+/// This awaits the first non-empty API keys fetch.
 pub async fn api_keys(
     client: reqwest::Client,
     url: Url,
     auth: String,
-) -> watch::Receiver<HashMap<String, APIKey>> {
-    let (tx, mut rx) = watch::channel(Default::default());
-    let mut client = Client { client, url, auth };
+    cache: Option<PathBuf>,
+    tx: Arc<watch::Sender<HashMap<String, APIKey>>>,
+) {
+    let mut rx = tx.subscribe();
+    let mut client = Client {
+        client,
+        url,
+        auth,
+        etag: None,
+    };
     tokio::spawn(async move {
         let mut interval = interval(Duration::from_secs(30));
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        // Time of the last successful fetch, or of the cached API keys in use
+        let mut last_sync: Option<SystemTime> = None;
+        // API keys of the last downloaded response
+        let mut last_api_keys: Option<HashMap<String, APIKey>> = None;
         loop {
             interval.tick().await;
 
             match client.fetch_api_keys().await {
-                Ok(api_keys) => {
-                    if let Err(api_keys_send_err) = tx.send(api_keys) {
-                        tracing::error!(%api_keys_send_err);
+                Ok(Some(response)) => match parse_api_keys(&response) {
+                    Ok(api_keys) => {
+                        last_sync = Some(SystemTime::now());
+                        last_api_keys = Some(api_keys.clone());
+                        if let Err(api_keys_send_err) = tx.send(api_keys) {
+                            tracing::error!(%api_keys_send_err);
+                        }
+                        if let Some(cache) = &cache {
+                            if let Err(api_keys_cache_err) = store_cache(cache, response).await {
+                                tracing::error!(%api_keys_cache_err);
+                            }
+                        }
+                    }
+                    Err(api_key_fetch_error) => {
+                        // Drop the ETag, to download the API keys again on the next fetch
+                        client.etag = None;
+                        METRICS.api_keys_sync_err.inc();
+                        tracing::error!(%api_key_fetch_error);
+                    }
+                },
+                // Not modified since the last fetch
+                Ok(None) => {
+                    last_sync = Some(SystemTime::now());
+                    if let Some(api_keys) = &last_api_keys {
+                        tx.send_replace(api_keys.clone());
+                    }
+                }
+                Err(api_key_fetch_error) => {
+                    METRICS.api_keys_sync_err.inc();
+                    tracing::error!(%api_key_fetch_error);
+                    if let (None, Some(cache)) = (last_sync, &cache) {
+                        match load_cache(cache.clone()).await {
+                            Ok((modified, api_keys)) => {
+                                tracing::warn!(api_keys = api_keys.len(), "using cached API keys");
+                                last_sync = Some(modified);
+                                tx.send_replace(api_keys);
+                            }
+                            Err(api_keys_cache_err) => tracing::error!(%api_keys_cache_err),
+                        }
                     }
                 }
-                Err(api_key_fetch_error) => tracing::error!(%api_key_fetch_error),
             };
+
+            if let Some(last_sync) = last_sync {
+                let age = last_sync.elapsed().unwrap_or_default();
+                METRICS.api_keys_sync_age_seconds.set(age.as_secs() as i64);
+            }
         }
     });
 
     rx.wait_for(|api_keys| !api_keys.is_empty()).await.unwrap();
-    rx
 }
 
 struct Client {
     client: reqwest::Client,
     url: Url,
     auth: String,
+    /// `ETag` of the last response
+    etag: Option<String>,
 }
 
 impl Client {
-    async fn fetch_api_keys(&mut self) -> anyhow::Result<HashMap<String, APIKey>> {
-        #[derive(Deserialize, Debug)]
-        struct _ApiKeys {
-            api_keys: Vec<_ApiKey>,
+    /// Fetch the API keys response body. Returns `None` if the API keys are unchanged since the
+    /// last fetch.
+    async fn fetch_api_keys(&mut self) -> anyhow::Result<Option<String>> {
+        let mut request = self.client.get(self.url.clone()).bearer_auth(&self.auth);
+        if let Some(etag) = &self.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
         }
-        #[derive(Deserialize, Debug)]
-        struct _ApiKey {
-            key: String,
-            user_address: String,
-            query_status: QueryStatus,
-            max_budget: Option<f64>,
-            #[serde(default)]
-            subgraphs: Vec<String>,
-            #[serde(default)]
-            domains: Vec<String>,
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
         }
+        let response = response.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let body = response.text().await?;
+        self.etag = etag;
+        Ok(Some(body))
+    }
+}
+
+fn parse_api_keys(response: &str) -> anyhow::Result<HashMap<String, APIKey>> {
+    #[derive(Deserialize, Debug)]
+    struct _ApiKeys {
+        api_keys: Vec<_ApiKey>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct _ApiKey {
+        key: String,
+        user_address: String,
+        query_status: QueryStatus,
+        max_budget: Option<f64>,
+        #[serde(default)]
+        monthly_cap: Option<f64>,
+        #[serde(default)]
+        subgraphs: Vec<String>,
+        #[serde(default)]
+        domains: Vec<String>,
+    }
+
+    let response: _ApiKeys = serde_json::from_str(response)?;
+    let api_keys = response
+        .api_keys
+        .into_iter()
+        .map(|api_key| {
+            let api_key = APIKey {
+                key: api_key.key,
+                user_address: api_key.user_address,
+                query_status: api_key.query_status,
+                domains: api_key.domains,
+                max_budget_usd: api_key.max_budget.and_then(|b| b.try_into().ok()),
+                subgraphs: api_key
+                    .subgraphs
+                    .into_iter()
+                    .filter_map(|s| s.parse().ok())
+                    .collect(),
+                rate_limit: None,
+                monthly_cap_usd: api_key.monthly_cap.and_then(|c| c.try_into().ok()),
+                ..Default::default()
+            };
+            (api_key.key.clone(), api_key)
+        })
+        .collect::<HashMap<String, APIKey>>();
+
+    tracing::info!(api_keys = api_keys.len());
+    Ok(api_keys)
+}
+
+/// Write the API keys response to the cache file.
+async fn store_cache(path: &Path, response: String) -> anyhow::Result<()> {
+    fs::write_atomic(path.to_path_buf(), response.into_bytes()).await
+}
+
+/// Load the API keys from the cache file, along with the time they were fetched.
+///
+/// The file is read and parsed on a blocking thread, to avoid blocking the async runtime.
+async fn load_cache(path: PathBuf) -> anyhow::Result<(SystemTime, HashMap<String, APIKey>)> {
+    tokio::task::spawn_blocking(move || load_cache_blocking(&path))
+        .await
+        .context("file read task failed")?
+}
+
+fn load_cache_blocking(path: &Path) -> anyhow::Result<(SystemTime, HashMap<String, APIKey>)> {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let response = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let api_keys = parse_api_keys(&response).context("invalid cached API keys")?;
+    Ok((modified, api_keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::{load_cache, parse_api_keys, store_cache};
+    use crate::{auth::QueryStatus, fs::temp_path};
+
+    const RESPONSE: &str = r#"{
+        "api_keys": [
+            {
+                "key": "0123456789abcdef0123456789abcdef",
+                "user_address": "0x0000000000000000000000000000000000000001",
+                "query_status": "MONTHLY_CAP_REACHED",
+                "max_budget": 0.001,
+                "subgraphs": ["invalid"]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn parse_studio_api_keys() {
+        //* When
+        let result = parse_api_keys(RESPONSE);
+
+        //* Then
+        assert_matches!(result, Ok(api_keys) => {
+            let api_key = &api_keys["0123456789abcdef0123456789abcdef"];
+            assert_matches!(api_key.query_status, QueryStatus::MonthlyCapReached);
+            assert_eq!(api_key.max_budget_usd.map(|b| *b), Some(0.001));
+            assert!(api_key.subgraphs.is_empty());
+        });
+    }
+
+    #[test]
+    fn reject_response_without_api_keys() {
+        //* When
+        let result = parse_api_keys("{}");
+
+        //* Then
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_studio_api_keys() {
+        //* Given
+        let path = temp_path("studio-api-keys.json");
+
+        //* When
+        store_cache(&path, RESPONSE.to_string())
+            .await
+            .expect("store cache");
+        let result = load_cache(path.clone()).await;
+        let _ = std::fs::remove_file(&path);
 
-        let response = self
-            .client
-            .get(self.url.clone())
-            .bearer_auth(&self.auth)
-            .send()
-            .await?
-            .json::<_ApiKeys>()
-            .await?;
-        let api_keys = response
-            .api_keys
-            .into_iter()
-            .map(|api_key| {
-                let api_key = APIKey {
-                    key: api_key.key,
-                    user_address: api_key.user_address,
-                    query_status: api_key.query_status,
-                    domains: api_key.domains,
-                    max_budget_usd: api_key.max_budget.and_then(|b| b.try_into().ok()),
-                    subgraphs: api_key
-                        .subgraphs
-                        .into_iter()
-                        .filter_map(|s| s.parse().ok())
-                        .collect(),
-                };
-                (api_key.key.clone(), api_key)
-            })
-            .collect::<HashMap<String, APIKey>>();
-
-        tracing::info!(api_keys = api_keys.len());
-        Ok(api_keys)
+        //* Then
+        assert_matches!(result, Ok((_, api_keys)) => {
+            assert_eq!(api_keys.len(), 1);
+        });
     }
 }
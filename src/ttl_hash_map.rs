@@ -144,6 +144,13 @@ where
         self.inner.clear();
     }
 
+    /// Retain only the non-expired entries for which the predicate returns true.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let ttl = self.ttl;
+        self.inner
+            .retain(|key, (timestamp, value)| (timestamp.elapsed() < ttl) && f(key, value));
+    }
+
     /// Cleanup the hashmap, removing all expired entries.
     ///
     /// After removing all expired entries, the inner hashmap is shrunk to fit the new capacity,
@@ -364,6 +371,25 @@ mod tests {
         assert!(ttl_hash_map.is_empty());
     }
 
+    #[test]
+    fn retain_the_matching_items_not_expired() {
+        //* Given
+        let mut ttl_hash_map = TtlHashMap::with_ttl(Duration::from_millis(5));
+
+        // Pre-populate the map
+        ttl_hash_map.insert("expired_item", 1);
+        std::thread::sleep(Duration::from_millis(10));
+        ttl_hash_map.insert("item_1", 2);
+        ttl_hash_map.insert("item_2", 3);
+
+        //* When
+        ttl_hash_map.retain(|_, value| *value != 3);
+
+        //* Then
+        assert_eq!(ttl_hash_map.len_all(), 1);
+        assert_eq!(ttl_hash_map.get(&"item_1"), Some(&2));
+    }
+
     #[test]
     fn cleanup_the_hashmap_and_shrink_to_fit() {
         //* Given
@@ -23,7 +23,7 @@ pub const UNATTESTABLE_ERROR_MESSAGE_FRAGMENTS: [&str; 22] = [
     "is larger than the allowed limit of",        // ResultTooBig
                                                   // TODO: ValidationError
 
-    // graph-node features
+    // graph-node features, also filtered out before candidate selection (see `query_features`)
     "\"block__timestamp\" does not exist",                                       // v0.28.0
     "Invalid value provided for argument `orderBy`: Enum(\"block__timestamp\")", // v0.28.0
     "ield \"and\" is not defined by type",                                       // v0.30.0